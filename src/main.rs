@@ -1,214 +1,5725 @@
 use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
 use std::fmt::Display;
-use std::path::PathBuf;
-use std::process::Command;
+use std::io::IsTerminal;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::num::NonZeroUsize;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
 
-use anyhow::{anyhow, Context, Result};
-use clap::Parser;
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{ArgEnum, CommandFactory, Parser};
+use clap_complete::Shell;
 use deunicode::deunicode;
 use rayon::prelude::*;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use unicode_normalization::UnicodeNormalization;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
 struct Track {
     file: PathBuf,
     disc: Option<u32>,
     track: Option<u32>,
+    position: Option<Position>,
     title: String,
     artist: Option<String>,
+    genre: Option<String>,
+    compression: Option<u32>,
+    quality: Option<u32>,
+    cover: Option<PathBuf>,
+    start: Option<Timestamp>,
+    end: Option<Timestamp>,
+    musicbrainz_trackid: Option<String>,
+    musicbrainz_albumid: Option<String>,
+    musicbrainz_artistid: Option<String>,
+    /// The track's position in the album, counting from 1, which is what --only and --skip use
+    #[serde(skip)]
+    number: usize,
+    /// The line of the CSV or file list that this track came from, for error messages
+    #[serde(skip)]
+    row: Option<usize>,
+    /// Start of the input file's SHA-256, added to the output filename for --hash-on-collision
+    #[serde(skip)]
+    name_hash: Option<String>,
+    /// Whether this is the first track of its disc in the CSV, for --cover-first-track-only
+    #[serde(skip)]
+    first_on_disc: bool,
 }
 
-#[derive(Debug, Parser)]
-#[clap(version, setting(clap::AppSettings::DeriveDisplayOrder))]
-struct Args {
-    /// Directory that input files are loacted in (default is the current directory)
-    #[clap(short = 'd', long)]
-    input_dir: Option<PathBuf>,
+/// A position in an audio file, in seconds. Parsed from either "[[HH:]MM:]SS[.sss]" or a plain
+/// number of seconds.
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Deserialize)]
+#[serde(try_from = "String")]
+struct Timestamp(f64);
 
-    /// Cover art file (jpg or png image. This path is always relative to the current directory,
-    /// not the directory specified by --input-dir)
-    #[clap(short, long)]
-    cover: Option<PathBuf>,
+impl FromStr for Timestamp {
+    type Err = anyhow::Error;
 
-    /// Album Title ('album' metadata field)
-    #[clap(short = 't', long)]
-    album_title: Option<String>,
+    fn from_str(s: &str) -> Result<Self> {
+        parse_duration(s)
+            .map(Self)
+            .ok_or_else(|| anyhow!("invalid timestamp '{s}'"))
+    }
+}
 
-    /// Album Artist ('album_artist' metadata field)
-    #[clap(short, long)]
-    album_artist: Option<String>,
+impl TryFrom<String> for Timestamp {
+    type Error = anyhow::Error;
 
-    /// Album date/year ('date' metadata field)
-    #[clap(short = 'y', long)]
-    date: Option<String>,
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
 
-    /// Number of parallel conversion tasks (default or 0 uses all CPU cores)
-    #[clap(short = 'j', long)]
-    threads: Option<usize>,
+impl Display for Timestamp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
 
-    /// Show verbose output, including which ffmpeg commands are run
-    #[clap(short, long)]
-    verbose: bool,
+/// A track's disc and track number from the CSV's position column, parsed from "DISC.TRACK" like
+/// "1.03", or just "TRACK" for albums without discs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+struct Position {
+    disc: Option<u32>,
+    track: u32,
+}
 
-    /// CSV file containing track information
-    ///
-    /// The input CSV should contain these columns:
-    ///     file    - The input filename, relative to the directory specified by --input-dir
-    ///     disc    - The disc number for this track
-    ///     track   - The track number for this track
-    ///     title   - The track's title
-    ///     artist  - The track's artist
-    ///
-    /// The disc and track columns are optional, or individual rows can have an empty value for
-    /// those columns. In this case, no disc or track metadata field will be added to the output
-    /// file.
-    #[clap(verbatim_doc_comment)]
-    input_csv: PathBuf,
+impl FromStr for Position {
+    type Err = anyhow::Error;
 
-    /// Directory in which to write output files
-    output_dir: PathBuf,
+    fn from_str(s: &str) -> Result<Self> {
+        let number = |n: &str| n.trim().parse().ok();
+        let position = match s.split_once('.') {
+            Some((disc, track)) => number(disc).zip(number(track)).map(|(disc, track)| Self {
+                disc: Some(disc),
+                track,
+            }),
+            None => number(s).map(|track| Self { disc: None, track }),
+        };
+        position.ok_or_else(|| anyhow!("invalid position '{s}', expected DISC.TRACK or TRACK"))
+    }
 }
 
-fn maybe_metadata<T: Display>(key: &str, val: &Option<T>) -> String {
-    match val {
-        Some(ref val) => format!("{key}={val}"),
-        None => String::new(),
+impl TryFrom<String> for Position {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
     }
 }
 
-impl Args {
-    fn convert_track(&self, track: &Track) -> Result<()> {
-        let input_file = match &self.input_dir {
-            Some(dir) => Cow::Owned(dir.join(&track.file)),
-            None => Cow::Borrowed(&track.file),
-        };
+impl Display for Position {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.disc {
+            Some(disc) => write!(f, "{disc}.{:02}", self.track),
+            None => write!(f, "{}", self.track),
+        }
+    }
+}
 
-        let artist = track
-            .artist
-            .as_deref()
-            .or(self.album_artist.as_deref())
-            .ok_or_else(|| {
-                anyhow!(
-                    "Unable to determine artist for track {}. Fill in the 'artist' CSV column or \
-                     use --album-artist",
-                    track.file.display(),
-                )
-            })?;
+/// A set of 1-based track indices, parsed from a list like "3,5-7"
+#[derive(Debug, Clone)]
+struct TrackIndices(Vec<RangeInclusive<usize>>);
 
-        let prefix = match (track.disc, track.track) {
-            (Some(disc), Some(track)) => format!("{disc}.{track:02}-"),
-            (Some(disc), None) => format!("{disc}-"),
-            (None, Some(track)) => format!("{track:02}-"),
-            (None, None) => String::new(),
+impl TrackIndices {
+    fn contains(&self, index: usize) -> bool {
+        self.0.iter().any(|range| range.contains(&index))
+    }
+
+    fn max(&self) -> usize {
+        self.0.iter().map(|range| *range.end()).max().unwrap_or(0)
+    }
+}
+
+impl Display for TrackIndices {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, range) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            match (range.start(), range.end()) {
+                (start, end) if start == end => write!(f, "{start}")?,
+                (start, end) => write!(f, "{start}-{end}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Serialized as the list it was parsed from, for --dump-config
+impl Serialize for TrackIndices {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl FromStr for TrackIndices {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parse_index = |s: &str| -> Result<usize> {
+            match s.trim().parse() {
+                Ok(0) => bail!("track indices start at 1"),
+                Ok(n) => Ok(n),
+                Err(_) => bail!("invalid track index '{}'", s.trim()),
+            }
         };
-        let output_file = self.output_dir.join(format!(
-            "{prefix}{artist}-{title}.flac",
-            artist = deunicode(artist),
-            title = deunicode(&track.title),
-        ));
 
-        let mut cmd = Command::new("ffmpeg");
-        cmd.args(["-hide_banner", "-nostdin", "-i"]);
-        cmd.arg(&*input_file);
-        if let Some(cover) = &self.cover {
-            cmd.arg("-i");
-            cmd.arg(cover);
-            cmd.args(["-map", "0:a", "-map", "1:v"]);
-        } else {
-            cmd.args(["-map", "0:a"]);
+        s.split(',')
+            .map(|part| {
+                let range = match part.split_once('-') {
+                    Some((start, end)) => parse_index(start)?..=parse_index(end)?,
+                    None => parse_index(part)?..=parse_index(part)?,
+                };
+                if range.is_empty() {
+                    bail!("invalid track range '{}'", part.trim());
+                }
+                Ok(range)
+            })
+            .collect::<Result<_>>()
+            .map(Self)
+    }
+}
+
+/// A field that can be parsed from a filename by --parse-filename
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FilenameField {
+    Artist,
+    Title,
+    Disc,
+    Track,
+    /// The date albumconv started, only for --name-template
+    Today,
+    /// The date and time albumconv started, only for --name-template
+    Now,
+}
+
+impl FilenameField {
+    /// The field's name in a pattern, without braces
+    fn name(self) -> &'static str {
+        match self {
+            Self::Artist => "artist",
+            Self::Title => "title",
+            Self::Disc => "disc",
+            Self::Track => "track",
+            Self::Today => "today",
+            Self::Now => "now",
         }
+    }
+}
 
-        let metadata = [
-            format!("title={}", track.title),
-            format!("artist={artist}"),
-            maybe_metadata("album", &self.album_title),
-            maybe_metadata("album_artist", &self.album_artist),
-            maybe_metadata("date", &self.date),
-            maybe_metadata("disc", &track.disc),
-            maybe_metadata("track", &track.track),
-        ];
-        for m in metadata.iter().filter(|s| !s.is_empty()) {
-            cmd.arg("-metadata");
-            cmd.arg(m);
-        }
-
-        if self.cover.is_some() {
-            cmd.args([
-                "-c:v",
-                "copy",
-                "-disposition:v",
-                "attached_pic",
-                "-metadata:s:v",
-                "comment=Cover (front)",
-            ]);
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum PatternPart {
+    Literal(String),
+    Field(FilenameField),
+}
+
+/// A pattern like "{track} - {artist} - {title}" for parsing track metadata from filenames
+#[derive(Debug, Clone)]
+struct FilenamePattern(Vec<PatternPart>);
+
+impl FromStr for FilenamePattern {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = Vec::new();
+        let mut rest = s;
+        while !rest.is_empty() {
+            let Some(start) = rest.find('{') else {
+                parts.push(PatternPart::Literal(rest.to_owned()));
+                break;
+            };
+            if start > 0 {
+                parts.push(PatternPart::Literal(rest[..start].to_owned()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| anyhow!("unterminated '{{' in filename pattern"))?
+                + start;
+            let field = match &rest[start + 1..end] {
+                "artist" => FilenameField::Artist,
+                "title" => FilenameField::Title,
+                "disc" => FilenameField::Disc,
+                "track" => FilenameField::Track,
+                "today" => FilenameField::Today,
+                "now" => FilenameField::Now,
+                name => bail!("unknown field '{{{name}}}' in filename pattern"),
+            };
+            if matches!(parts.last(), Some(PatternPart::Field(_))) {
+                bail!("fields in filename pattern must be separated by some text");
+            }
+            parts.push(PatternPart::Field(field));
+            rest = &rest[end + 1..];
         }
-        cmd.args(["-c:a", "flac", "-y"]);
-        cmd.arg(&output_file);
 
-        if self.verbose {
-            println!("+ {cmd:?}");
+        if !parts.contains(&PatternPart::Field(FilenameField::Title)) {
+            bail!("filename pattern must contain {{title}}");
         }
+        Ok(Self(parts))
+    }
+}
 
-        let output = cmd
-            .output()
-            .with_context(|| "Failed to execute ffmpeg {cmd:?}")?;
-        if output.status.success() {
-            println!("OK: {}", output_file.display());
-            Ok(())
-        } else {
-            Err(anyhow!(
-                "failed to convert {infile} into {outfile}: ffmpeg command failed\n\
-                 \n\
-                 command: {cmd:?}\n\
-                 \n\
-                 standard output:\n\
-                 {stdout}\n\
-                 \n\
-                 standard error:\n\
-                 {stderr}\n",
-                infile = track.file.display(),
-                outfile = output_file.display(),
-                cmd = cmd,
-                stdout = String::from_utf8_lossy(&output.stdout),
-                stderr = String::from_utf8_lossy(&output.stderr),
-            ))
+impl Display for FilenamePattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for part in &self.0 {
+            match part {
+                PatternPart::Literal(text) => write!(f, "{text}")?,
+                PatternPart::Field(field) => write!(f, "{{{}}}", field.name())?,
+            }
         }
+        Ok(())
     }
 }
 
-fn run() -> Result<()> {
-    let args = Args::parse();
+/// Serialized as a pattern string, for --dump-config
+impl Serialize for FilenamePattern {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl FilenamePattern {
+    /// Whether this pattern uses the {today} or {now} fields
+    fn has_time_fields(&self) -> bool {
+        self.0.iter().any(|part| {
+            matches!(
+                part,
+                PatternPart::Field(FilenameField::Today | FilenameField::Now)
+            )
+        })
+    }
+
+    /// Remove a trailing file extension like ".flac" from the end of this pattern, and return it
+    /// without the dot
+    fn take_extension(&mut self) -> Option<String> {
+        let Some(PatternPart::Literal(text)) = self.0.last_mut() else {
+            return None;
+        };
+        let (rest, ext) = text.rsplit_once('.')?;
+        if ext.is_empty() || !ext.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return None;
+        }
+        let ext = ext.to_owned();
+        text.truncate(rest.len());
+        if text.is_empty() {
+            self.0.pop();
+        }
+        Some(ext)
+    }
+
+    /// Fill in this pattern's fields to build a filename
+    fn format(&self, value: impl Fn(FilenameField) -> String) -> String {
+        self.0
+            .iter()
+            .map(|part| match part {
+                PatternPart::Literal(text) => text.clone(),
+                PatternPart::Field(field) => value(*field),
+            })
+            .collect()
+    }
+
+    /// Match a filename (without extension) against this pattern, returning the value of each
+    /// field. Each field matches up to the first occurrence of the text that follows it.
+    fn parse<'a>(&self, name: &'a str) -> Option<Vec<(FilenameField, &'a str)>> {
+        let mut fields = Vec::new();
+        let mut rest = name;
+        for (i, part) in self.0.iter().enumerate() {
+            match part {
+                PatternPart::Literal(text) => rest = rest.strip_prefix(text.as_str())?,
+                PatternPart::Field(field) => {
+                    let end = match self.0.get(i + 1) {
+                        Some(PatternPart::Literal(text)) => rest.find(text.as_str())?,
+                        _ => rest.len(),
+                    };
+                    fields.push((*field, rest[..end].trim()));
+                    rest = &rest[end..];
+                }
+            }
+        }
+        rest.is_empty().then_some(fields)
+    }
+
+    /// Build a Track for an input file by parsing its name
+    fn track(&self, file: PathBuf) -> Result<Track> {
+        let name = file
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .ok_or_else(|| anyhow!("invalid filename {}", file.display()))?;
+        let fields = self.parse(name).ok_or_else(|| {
+            anyhow!(
+                "filename '{}' doesn't match the --parse-filename pattern",
+                file.display()
+            )
+        })?;
 
-    if let Some(threads) = args.threads {
-        rayon::ThreadPoolBuilder::new()
-            .num_threads(threads)
-            .build_global()
-            .context("failed to initialize rayon global thread pool")?;
+        let mut track = Track::default();
+        for (field, value) in fields {
+            let number = || {
+                value.parse().with_context(|| {
+                    format!("invalid number '{value}' in filename '{}'", file.display())
+                })
+            };
+            match field {
+                FilenameField::Artist => track.artist = Some(value.to_owned()),
+                FilenameField::Title => track.title = value.to_owned(),
+                FilenameField::Disc => track.disc = Some(number()?),
+                FilenameField::Track => track.track = Some(number()?),
+                // rejected when validating --parse-filename
+                FilenameField::Today | FilenameField::Now => (),
+            }
+        }
+        track.file = file;
+        Ok(track)
     }
+}
+
+/// File extensions that are considered audio files when scanning a directory for input files
+const AUDIO_EXTENSIONS: &[&str] = &[
+    "aif", "aiff", "alac", "ape", "flac", "m4a", "mp3", "ogg", "opus", "wav", "wma", "wv",
+];
+
+/// Cover art filenames that --auto-cover looks for, in order of preference
+const COVER_NAMES: &[&str] = &[
+    "cover.jpg",
+    "cover.png",
+    "folder.jpg",
+    "folder.png",
+    "front.jpg",
+    "front.png",
+];
 
+/// Codecs reported by ffprobe for lossy audio, which gains nothing from lossless output
+const LOSSY_CODECS: &[&str] = &[
+    "aac", "ac3", "eac3", "mp2", "mp3", "opus", "vorbis", "wmav1", "wmav2",
+];
+
+/// Read the --batch-metadata CSV, keyed by album name
+fn read_batch_metadata(path: &Path) -> Result<HashMap<String, AlbumMetadata>> {
     let mut reader = csv::ReaderBuilder::new()
         .trim(csv::Trim::All)
-        .from_path(&args.input_csv)
-        .context("failed to open input file")?;
+        .from_path(path)
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    let mut albums = HashMap::new();
+    for row in reader.deserialize() {
+        let row: AlbumMetadata =
+            row.with_context(|| format!("failed to parse {}", path.display()))?;
+        if albums.contains_key(&row.name) {
+            bail!(
+                "album '{}' is listed more than once in {}",
+                row.name,
+                path.display()
+            );
+        }
+        albums.insert(row.name.clone(), row);
+    }
+    Ok(albums)
+}
 
-    std::fs::create_dir_all(&args.output_dir).context("failed to create output directory")?;
+/// Parse a disc number from a directory name like "CD2", "cd 2", or "Disc 2 - Bonus" for
+/// --disc-from-subdir
+fn disc_from_dir_name(name: &str) -> Option<u32> {
+    let lower = name.to_ascii_lowercase();
+    let rest = ["cd", "disc", "disk"]
+        .iter()
+        .find_map(|prefix| lower.strip_prefix(prefix))?
+        .trim_start();
+    let digits = rest.chars().take_while(char::is_ascii_digit).count();
+    rest[..digits].parse().ok()
+}
 
-    // Neat, you can collect from an iterator of Results into a Result of a collection. Returns
-    // Ok(collection) if every value was Ok, or Err(e) of the first Err item.
-    let tracks = reader
-        .deserialize()
-        .collect::<Result<Vec<Track>, _>>()
-        .context("failed to parse CSV file")?;
+/// List the files in a directory which have one of the given extensions, in sorted order
+fn list_dir(dir: &Path, extensions: &[&str]) -> Result<Vec<PathBuf>> {
+    let mut files = std::fs::read_dir(dir)
+        .and_then(|entries| {
+            entries
+                .map(|entry| entry.map(|e| e.path()))
+                .collect::<io::Result<Vec<_>>>()
+        })
+        .with_context(|| format!("failed to read directory {}", dir.display()))?;
+    files.retain(|path| {
+        path.extension().is_some_and(|ext| {
+            extensions
+                .iter()
+                .any(|wanted| ext.eq_ignore_ascii_case(wanted))
+        })
+    });
+    files.sort();
+    Ok(files)
+}
 
-    // short-circuits returning the first error, or Ok(()) on success
-    tracks
-        .par_iter()
-        .try_for_each(|track| args.convert_track(track))
+/// Loudness measurement of a single track
+#[derive(Debug, Clone, Copy)]
+struct Loudness {
+    /// Integrated loudness in LUFS
+    integrated: f64,
+    /// Duration in seconds
+    duration: f64,
 }
 
-fn main() {
-    if let Err(err) = run() {
-        println!("Error: {err:#}");
-        std::process::exit(1);
+/// Target loudness for --album-normalize, the ReplayGain 2.0 reference level
+const ALBUM_TARGET_LUFS: f64 = -18.0;
+
+/// Maximum width and height of cover art with --compat
+const COMPAT_COVER_SIZE: u32 = 600;
+
+/// Compute the gain (in dB) needed to bring the whole album to ALBUM_TARGET_LUFS. The album's
+/// loudness is the average of all tracks' loudness in the energy domain, weighted by duration.
+fn album_gain(tracks: &[Loudness]) -> f64 {
+    let total_duration: f64 = tracks.iter().map(|t| t.duration).sum();
+    let energy: f64 = tracks
+        .iter()
+        .map(|t| t.duration * 10f64.powf(t.integrated / 10.0))
+        .sum();
+    ALBUM_TARGET_LUFS - 10.0 * (energy / total_duration).log10()
+}
+
+/// Parse a duration like "00:03:21.12", "3:21", or "201.12" into seconds
+fn parse_duration(s: &str) -> Option<f64> {
+    let mut seconds = 0.0;
+    let mut parts = 0;
+    for part in s.trim().split(':') {
+        let value: f64 = part.parse().ok()?;
+        if !value.is_finite() || value < 0.0 {
+            return None;
+        }
+        seconds = seconds * 60.0 + value;
+        parts += 1;
+    }
+    (parts <= 3).then_some(seconds)
+}
+
+/// Parse a size like "500M", "2G", or "1.5GiB" into bytes. Suffixes are binary, so "1K" is 1024
+/// bytes, and a plain number is bytes.
+fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (number, suffix) = s.split_at(split);
+    let number: f64 = number.parse().map_err(|_| anyhow!("invalid size '{s}'"))?;
+    let suffix = suffix.trim().to_ascii_lowercase();
+    let unit = suffix.trim_end_matches('b').trim_end_matches('i');
+    let shift = match unit {
+        "" => 0,
+        "k" => 10,
+        "m" => 20,
+        "g" => 30,
+        "t" => 40,
+        _ => bail!("invalid size '{s}', expected a suffix like K, M, or G"),
+    };
+    Ok((number * (1u64 << shift) as f64) as u64)
+}
+
+/// Parse a KEY=VALUE tag for --meta
+fn parse_meta(s: &str) -> Result<(String, String)> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => {
+            Ok((key.trim().to_owned(), value.to_owned()))
+        }
+        _ => bail!("invalid tag '{s}', expected KEY=VALUE"),
+    }
+}
+
+/// Check a FLAC compression level from --compression-level or the compression CSV column, given
+/// the output formats
+fn check_compression(formats: &[Format], level: u32) -> Result<()> {
+    if !formats.contains(&Format::Flac) {
+        bail!("compression levels can only be used with FLAC output");
+    }
+    if level > 12 {
+        bail!("compression level {level} is out of range, it must be from 0 to 12");
+    }
+    Ok(())
+}
+
+/// Check an MP3 quality from --quality or the quality CSV column, given the output formats
+fn check_quality(formats: &[Format], quality: u32) -> Result<()> {
+    if !formats.contains(&Format::Mp3) {
+        bail!("quality can only be used with MP3 output");
+    }
+    if quality > 9 {
+        bail!("quality {quality} is out of range, it must be from 0 to 9");
+    }
+    Ok(())
+}
+
+/// Parse a JPEG quality for --cover-quality
+fn parse_cover_quality(s: &str) -> Result<u32> {
+    match s.trim().parse() {
+        Ok(quality @ 1..=31) => Ok(quality),
+        _ => bail!("invalid cover quality '{s}', it must be from 1 to 31"),
+    }
+}
+
+/// Check an audio bitrate like "128k" or "96000" for --bitrate and --max-bitrate
+fn parse_bitrate(s: &str) -> Result<String> {
+    let digits = s.strip_suffix(['k', 'K']).unwrap_or(s);
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+        bail!("invalid bitrate '{s}', expected something like '128k'");
+    }
+    Ok(s.to_lowercase())
+}
+
+/// Parse an octal file mode like "644" or "0o2775" for --chmod and --dir-chmod
+fn parse_mode(s: &str) -> Result<u32> {
+    let digits = s.strip_prefix("0o").unwrap_or(s);
+    match u32::from_str_radix(digits, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => bail!("invalid file mode '{s}', expected an octal mode like '644'"),
+    }
+}
+
+/// Set the permissions of a file or directory to a mode from --chmod or --dir-chmod. This does
+/// nothing outside of Unix.
+fn set_mode(path: &Path, mode: u32) -> Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+            .with_context(|| format!("failed to set permissions of {}", path.display()))?;
+    }
+    #[cfg(not(unix))]
+    let _ = (path, mode);
+    Ok(())
+}
+
+/// Find the path of a file relative to a directory, adding ".." components as needed. Both paths
+/// should be absolute. If they have nothing in common, like on different Windows drives, the
+/// file's path is returned unchanged.
+fn relative_path(dir: &Path, file: &Path) -> PathBuf {
+    let common = dir
+        .components()
+        .zip(file.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    if common == 0 {
+        return file.to_owned();
+    }
+    let mut path = PathBuf::new();
+    for _ in dir.components().skip(common) {
+        path.push("..");
+    }
+    path.extend(file.components().skip(common));
+    path
+}
+
+/// The local time when albumconv started, for the {today} and {now} tokens. It's captured once so
+/// that every track in a run gets the same value, even if the run goes past midnight.
+#[derive(Debug, Default, Clone, Copy)]
+struct StartTime {
+    year: i64,
+    month: u32,
+    day: u32,
+    hour: u32,
+    minute: u32,
+    second: u32,
+}
+
+impl StartTime {
+    /// The current local time. Outside of Unix, this is UTC.
+    fn capture() -> Self {
+        #[cfg(unix)]
+        {
+            // SAFETY: localtime_r only writes to the tm struct that we pass it
+            let mut tm = unsafe { std::mem::zeroed::<libc::tm>() };
+            let time = unsafe { libc::time(std::ptr::null_mut()) };
+            if !unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+                return Self {
+                    year: i64::from(tm.tm_year) + 1900,
+                    month: tm.tm_mon as u32 + 1,
+                    day: tm.tm_mday as u32,
+                    hour: tm.tm_hour as u32,
+                    minute: tm.tm_min as u32,
+                    second: tm.tm_sec as u32,
+                };
+            }
+        }
+        let secs = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as i64);
+        // Convert days since 1970-01-01 to a civil date, from Howard Hinnant's days_from_civil
+        // algorithms: http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+        let z = secs.div_euclid(86400) + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = yoe + era * 400 + i64::from(month <= 2);
+        let time = secs.rem_euclid(86400) as u32;
+        Self {
+            year,
+            month,
+            day,
+            hour: time / 3600,
+            minute: time / 60 % 60,
+            second: time % 60,
+        }
+    }
+
+    /// The date, for {today}
+    fn today(&self) -> String {
+        format!("{:04}-{:02}-{:02}", self.year, self.month, self.day)
+    }
+
+    /// The date and time, for {now}. This uses dashes rather than colons in the time, since
+    /// colons aren't allowed in Windows filenames.
+    fn now(&self) -> String {
+        format!(
+            "{}T{:02}-{:02}-{:02}",
+            self.today(),
+            self.hour,
+            self.minute,
+            self.second
+        )
+    }
+
+    /// Replace {today} and {now} in a path. Anything else in braces is left alone.
+    fn expand(&self, path: &Path) -> PathBuf {
+        match path.to_str() {
+            Some(s) if s.contains('{') => s
+                .replace("{today}", &self.today())
+                .replace("{now}", &self.now())
+                .into(),
+            _ => path.to_owned(),
+        }
+    }
+}
+
+/// Read a number from ffprobe's JSON output. ffprobe prints most numbers as strings, and leaves out
+/// values it doesn't know.
+fn probe_number(value: &serde_json::Value) -> Option<f64> {
+    value
+        .as_str()
+        .and_then(|s| s.parse().ok())
+        .or_else(|| value.as_f64())
+        .filter(|n| *n > 0.0)
+}
+
+/// Format a duration in seconds as [H:]MM:SS, rounded to the nearest second
+fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.round() as u64;
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{hours}:{minutes:02}:{seconds:02}")
+    } else {
+        format!("{minutes}:{seconds:02}")
+    }
+}
+
+/// Convert a bitrate from parse_bitrate to bits per second
+fn bitrate_bps(bitrate: &str) -> f64 {
+    match bitrate.strip_suffix('k') {
+        Some(kbps) => kbps.parse::<f64>().unwrap_or(0.0) * 1000.0,
+        None => bitrate.parse().unwrap_or(0.0),
+    }
+}
+
+/// Compute the SHA-256 of a file
+fn sha256_file(path: &Path) -> Result<String> {
+    let mut file =
+        std::fs::File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
+}
+
+/// Format a number of bytes for messages, in MiB
+fn format_size(bytes: u64) -> String {
+    format!("{:.1} MiB", bytes as f64 / (1u64 << 20) as f64)
+}
+
+/// Get the space available to unprivileged users on the filesystem containing a path. If the path
+/// doesn't exist yet, its closest existing parent directory is checked instead.
+fn free_space(path: &Path) -> Result<u64> {
+    let dir = path
+        .ancestors()
+        .find(|dir| dir.exists())
+        .unwrap_or(Path::new("."));
+    fs4::available_space(dir)
+        .with_context(|| format!("failed to get free space for {}", dir.display()))
+}
+
+/// The result of processing a single track
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Outcome {
+    /// The track was converted successfully
+    Converted,
+    /// The track wasn't converted, e.g. because an earlier track failed
+    Skipped,
+    /// Conversion failed
+    Failed,
+}
+
+/// Report entry for a single track
+#[derive(Debug, Serialize)]
+struct TrackReport {
+    file: PathBuf,
+    output: Option<PathBuf>,
+    outcome: Outcome,
+    error: Option<String>,
+}
+
+/// Counts of track outcomes, printed at the end of a run
+#[derive(Debug, Default, Serialize)]
+struct Summary {
+    converted: usize,
+    skipped: usize,
+    failed: usize,
+    tracks: Vec<TrackReport>,
+    /// Number of tracks listed in the input, before --only and --skip, to tell an empty CSV apart
+    /// from one where every track was skipped
+    #[serde(skip)]
+    listed: usize,
+    /// The first error that occurred
+    #[serde(skip)]
+    error: Option<anyhow::Error>,
+}
+
+impl Summary {
+    fn record(&mut self, file: PathBuf, output: Option<PathBuf>, result: Result<Outcome>) {
+        let (outcome, error) = match result {
+            Ok(outcome) => (outcome, None),
+            Err(err) => {
+                let msg = format!("{err:#}");
+                self.error.get_or_insert(err);
+                (Outcome::Failed, Some(msg))
+            }
+        };
+        self.tracks.push(TrackReport {
+            file,
+            output,
+            outcome,
+            error,
+        });
+        match outcome {
+            Outcome::Converted => self.converted += 1,
+            Outcome::Skipped => self.skipped += 1,
+            Outcome::Failed => self.failed += 1,
+        }
+    }
+
+    fn merge(&mut self, other: Summary) {
+        self.converted += other.converted;
+        self.skipped += other.skipped;
+        self.failed += other.failed;
+        self.tracks.extend(other.tracks);
+        self.listed += other.listed;
+        if self.error.is_none() {
+            self.error = other.error;
+        }
+    }
+}
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} converted, {} skipped, {} failed",
+            self.converted, self.skipped, self.failed
+        )
+    }
+}
+
+/// What converting a track would do to the existing output file, for --compare-existing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Disposition {
+    /// There's no output file yet
+    New,
+    /// The output file is already in the output format and has all of the tags
+    Unchanged,
+    /// The output file is in the output format, but its tags would change
+    Retag,
+    /// The output file is in a different format
+    Reencode,
+}
+
+impl Disposition {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::New => "new",
+            Self::Unchanged => "unchanged",
+            Self::Retag => "re-tag",
+            Self::Reencode => "re-encode",
+        }
+    }
+}
+
+/// Format rows of cells as a table with aligned columns, for --list-tracks and similar options
+fn format_table<const N: usize>(rows: &[[String; N]]) -> String {
+    let mut widths = [0; N];
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let mut table = String::new();
+    for row in rows {
+        let cells = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect::<Vec<_>>();
+        table += cells.join("  ").trim_end();
+        table.push('\n');
+    }
+    table
+}
+
+/// Output audio format. ffmpeg can decode Monkey's Audio (APE) but has no encoder for it, so it
+/// can't be an output format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Format {
+    Flac,
+    Mp3,
+    Opus,
+    #[clap(name = "wavpack")]
+    #[serde(rename = "wavpack")]
+    WavPack,
+}
+
+impl Format {
+    const ALL: [Self; 4] = [Self::Flac, Self::Mp3, Self::Opus, Self::WavPack];
+
+    /// Find the format for a file extension, ignoring case
+    fn from_extension(ext: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|format| ext.eq_ignore_ascii_case(format.extension()))
+    }
+
+    /// The ffmpeg audio encoder for this format
+    fn codec(self) -> &'static str {
+        match self {
+            Self::Flac => "flac",
+            Self::Mp3 => "libmp3lame",
+            Self::Opus => "libopus",
+            Self::WavPack => "wavpack",
+        }
+    }
+
+    /// Whether this format is lossless, and so has no bitrate setting
+    fn lossless(self) -> bool {
+        match self {
+            Self::Flac | Self::WavPack => true,
+            Self::Mp3 | Self::Opus => false,
+        }
+    }
+
+    /// Whether this format can hold embedded cover art. ffmpeg can't write cover art to Ogg or
+    /// WavPack files, so Opus and WavPack can't.
+    fn supports_cover(self) -> bool {
+        match self {
+            Self::Flac | Self::Mp3 => true,
+            Self::Opus | Self::WavPack => false,
+        }
+    }
+
+    /// The codec name that ffprobe reports for audio in this format
+    fn probe_codec(self) -> &'static str {
+        match self {
+            Self::Flac => "flac",
+            Self::Mp3 => "mp3",
+            Self::Opus => "opus",
+            Self::WavPack => "wavpack",
+        }
+    }
+
+    /// The ffmpeg muxer name for this format, which is also the file extension
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Flac => "flac",
+            Self::Mp3 => "mp3",
+            Self::Opus => "opus",
+            Self::WavPack => "wv",
+        }
+    }
+}
+
+/// ID3v2 tag version for MP3 output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum Id3Version {
+    #[clap(name = "2.3")]
+    #[serde(rename = "2.3")]
+    V2_3,
+    #[clap(name = "2.4")]
+    #[serde(rename = "2.4")]
+    V2_4,
+}
+
+/// Unicode normalization form for tag values
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum UnicodeForm {
+    /// Composed characters, e.g. 'é' as a single codepoint
+    Nfc,
+    /// Decomposed characters, e.g. 'é' as 'e' followed by a combining accent
+    Nfd,
+    /// Leave tags as they are
+    None,
+}
+
+impl UnicodeForm {
+    /// Normalize a string to this form
+    fn apply(self, s: &str) -> String {
+        match self {
+            Self::Nfc => s.nfc().collect(),
+            Self::Nfd => s.nfd().collect(),
+            Self::None => s.to_owned(),
+        }
+    }
+}
+
+/// What to do when converting lossy audio to a lossless format, for --lossy-source
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum LossySource {
+    /// Print a warning and convert the track anyway
+    Warn,
+    /// Don't convert the track
+    Skip,
+}
+
+/// Format of the report printed at the end of a run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum ReportFormat {
+    Text,
+    Json,
+}
+
+/// Opus encoder application type, which tunes the encoder for different kinds of audio
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum OpusApplication {
+    /// Music and other general audio
+    Audio,
+    /// Speech, e.g. audiobooks and podcasts
+    Voip,
+    /// Minimize latency
+    Lowdelay,
+}
+
+/// ffmpeg's -loglevel for converting tracks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum FfmpegLogLevel {
+    Quiet,
+    Error,
+    Warning,
+    Info,
+    Verbose,
+}
+
+impl FfmpegLogLevel {
+    /// The value to pass to ffmpeg's -loglevel option
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Quiet => "quiet",
+            Self::Error => "error",
+            Self::Warning => "warning",
+            Self::Info => "info",
+            Self::Verbose => "verbose",
+        }
+    }
+}
+
+/// A row of the --batch-metadata CSV
+#[derive(Debug, Deserialize)]
+struct AlbumMetadata {
+    /// The album's CSV filename without the .csv extension
+    name: String,
+    album: Option<String>,
+    album_artist: Option<String>,
+    date: Option<String>,
+    cover: Option<PathBuf>,
+}
+
+/// Surround to stereo downmix for --downmix-filter
+#[derive(Debug, Clone, PartialEq)]
+enum Downmix {
+    /// Dolby Pro Logic II compatible matrix encoding
+    Dolby,
+    /// Mix center and surround channels into the front channels at -3 dB
+    Simple,
+    /// Arguments for ffmpeg's pan filter, e.g. "stereo|FL=FL+FC|FR=FR+FC"
+    Custom(String),
+}
+
+impl FromStr for Downmix {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim() {
+            "" => bail!("downmix filter can't be empty"),
+            "dolby" => Ok(Self::Dolby),
+            "simple" => Ok(Self::Simple),
+            custom => {
+                let custom = custom.strip_prefix("pan=").unwrap_or(custom);
+                Ok(Self::Custom(custom.to_owned()))
+            }
+        }
+    }
+}
+
+impl Display for Downmix {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Dolby => write!(f, "dolby"),
+            Self::Simple => write!(f, "simple"),
+            Self::Custom(args) => write!(f, "{args}"),
+        }
+    }
+}
+
+/// Serialized like the --downmix-filter argument, for --dump-config
+impl Serialize for Downmix {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl Downmix {
+    /// The ffmpeg audio filter for this downmix. Using '<' instead of '=' in the channel
+    /// definitions makes the pan filter normalize the gains so that the output doesn't clip.
+    fn filter(&self) -> String {
+        match self {
+            Self::Dolby => "pan=stereo\
+                            |FL<FL+0.707*FC-0.8719*BL-0.4891*BR\
+                            |FR<FR+0.707*FC+0.4891*BL+0.8719*BR"
+                .to_owned(),
+            Self::Simple => "pan=stereo|FL<FL+0.707*FC+0.707*BL|FR<FR+0.707*FC+0.707*BR".to_owned(),
+            Self::Custom(args) => format!("pan={args}"),
+        }
+    }
+}
+
+/// Where the cover art for a track comes from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CoverArt<'a> {
+    None,
+    /// An image file, from --cover or the CSV's cover column
+    File(&'a Path),
+    /// Art that's already embedded in the input file
+    Embedded,
+}
+
+/// When to use colored output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum ColorChoice {
+    /// Use color if standard output is a terminal
+    Auto,
+    Always,
+    Never,
+}
+
+/// Exit code when the input has no tracks, so that an empty CSV isn't mistaken for a successful
+/// run. Tracks left out by --only and --skip still count.
+const EXIT_NO_TRACKS: i32 = 2;
+
+/// Whether to color status messages, set once at startup from --color
+static USE_COLOR: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy)]
+enum Style {
+    Green,
+    Yellow,
+    Red,
+}
+
+impl Style {
+    /// Wrap text in ANSI color codes if color is enabled
+    fn paint(self, text: &str) -> Cow<'_, str> {
+        if !USE_COLOR.load(Ordering::Relaxed) {
+            return Cow::Borrowed(text);
+        }
+        let code = match self {
+            Self::Green => 32,
+            Self::Yellow => 33,
+            Self::Red => 31,
+        };
+        Cow::Owned(format!("\x1b[{code}m{text}\x1b[0m"))
+    }
+}
+
+/// A counting semaphore which limits how many tracks are converted at once, for --max-inflight
+#[derive(Debug)]
+struct Semaphore {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+impl Semaphore {
+    fn new(permits: usize) -> Self {
+        Self {
+            available: Mutex::new(permits),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Wait for a permit, which is released when the returned guard is dropped
+    fn acquire(&self) -> SemaphoreGuard<'_> {
+        let mut available = self.available.lock().expect("semaphore lock poisoned");
+        while *available == 0 {
+            available = self
+                .released
+                .wait(available)
+                .expect("semaphore lock poisoned");
+        }
+        *available -= 1;
+        SemaphoreGuard(self)
+    }
+}
+
+struct SemaphoreGuard<'a>(&'a Semaphore);
+
+impl Drop for SemaphoreGuard<'_> {
+    fn drop(&mut self) {
+        *self.0.available.lock().expect("semaphore lock poisoned") += 1;
+        self.0.released.notify_one();
+    }
+}
+
+/// Set by the --summary-on-sigint handler when the first Ctrl-C is received, after which no new
+/// tracks are started
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Running count of converted, skipped, and failed tracks, printed by --summary-on-sigint
+static PROGRESS: [AtomicUsize; 3] = [
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+    AtomicUsize::new(0),
+];
+
+impl Outcome {
+    /// Add this outcome to the running count in PROGRESS
+    fn tally(self) {
+        PROGRESS[self as usize].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Set when --summary-on-sigint is used, so that commands are run in their own process groups.
+/// Ctrl-C sends SIGINT to the terminal's whole foreground process group, which would otherwise
+/// stop every running ffmpeg along with albumconv.
+#[cfg(unix)]
+static SEPARATE_PROCESS_GROUPS: AtomicBool = AtomicBool::new(false);
+
+/// Process IDs of the commands that are running in their own process groups, which are also
+/// their process group IDs
+#[cfg(unix)]
+static RUNNING_CHILDREN: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+/// Removes a child from RUNNING_CHILDREN when it's dropped
+#[cfg(unix)]
+struct RunningChild(u32);
+
+#[cfg(unix)]
+impl RunningChild {
+    fn new(pid: u32) -> Self {
+        RUNNING_CHILDREN.lock().expect("lock poisoned").push(pid);
+        Self(pid)
+    }
+}
+
+#[cfg(unix)]
+impl Drop for RunningChild {
+    fn drop(&mut self) {
+        RUNNING_CHILDREN
+            .lock()
+            .expect("lock poisoned")
+            .retain(|&pid| pid != self.0);
+    }
+}
+
+/// Install a SIGINT handler for --summary-on-sigint. On the first Ctrl-C, print the tracks
+/// finished so far and let tracks that are already running finish. On the second, stop the
+/// running commands and exit immediately.
+///
+/// Printing isn't async-signal-safe, so the handler only writes a byte to a pipe and a separate
+/// thread does the printing. If that thread can't keep up, a third Ctrl-C exits from the handler.
+#[cfg(unix)]
+fn install_sigint_handler() -> Result<()> {
+    use std::fs::File;
+    use std::os::unix::io::FromRawFd;
+    use std::sync::atomic::AtomicI32;
+
+    static PIPE_WRITE_FD: AtomicI32 = AtomicI32::new(-1);
+    static SIGNALS: AtomicUsize = AtomicUsize::new(0);
+
+    extern "C" fn handler(_signum: libc::c_int) {
+        // SAFETY: _exit and write are both async-signal-safe
+        unsafe {
+            INTERRUPTED.store(true, Ordering::SeqCst);
+            if SIGNALS.fetch_add(1, Ordering::SeqCst) >= 2 {
+                libc::_exit(130);
+            }
+            let fd = PIPE_WRITE_FD.load(Ordering::SeqCst);
+            libc::write(fd, [0u8].as_ptr().cast(), 1);
+        }
+    }
+
+    let mut fds = [0; 2];
+    // SAFETY: fds has room for the two file descriptors that pipe writes
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error()).context("failed to create signal pipe");
+    }
+    PIPE_WRITE_FD.store(fds[1], Ordering::SeqCst);
+    // SAFETY: the read end of the pipe was just created and nothing else owns it
+    let mut pipe_read = unsafe { File::from_raw_fd(fds[0]) };
+
+    std::thread::spawn(move || {
+        let mut buf = [0u8];
+        if pipe_read.read_exact(&mut buf).is_ok() {
+            let [converted, skipped, failed] = PROGRESS
+                .each_ref()
+                .map(|count| count.load(Ordering::Relaxed));
+            eprintln!(
+                "{} so far: {converted} converted, {skipped} skipped, {failed} failed. \
+                 Waiting for running tracks to finish, press Ctrl-C again to exit immediately.",
+                Style::Yellow.paint("Interrupted"),
+            );
+        }
+        if pipe_read.read_exact(&mut buf).is_ok() {
+            for &pid in RUNNING_CHILDREN.lock().expect("lock poisoned").iter() {
+                // SAFETY: kill is safe to call with any arguments. A negative PID signals the
+                // whole process group, which is the child and anything it started.
+                unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGTERM) };
+            }
+            std::process::exit(130);
+        }
+    });
+
+    // SAFETY: handler only calls async-signal-safe functions
+    let prev = unsafe { libc::signal(libc::SIGINT, handler as *const () as libc::sighandler_t) };
+    if prev == libc::SIG_ERR {
+        return Err(io::Error::last_os_error()).context("failed to install SIGINT handler");
+    }
+    SEPARATE_PROCESS_GROUPS.store(true, Ordering::SeqCst);
+    Ok(())
+}
+
+/// How output file paths are written in generated files, for --path-style
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum PathStyle {
+    /// Relative to the directory of the file they're written in
+    Relative,
+    /// Absolute paths
+    Absolute,
+}
+
+/// Format of the album metadata file written by --sidecar
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum SidecarFormat {
+    /// album.json
+    Json,
+    /// album.nfo, in Kodi's format
+    Nfo,
+}
+
+/// Album metadata written by --sidecar
+#[derive(Debug, Serialize)]
+struct Sidecar<'a> {
+    album: Option<&'a str>,
+    album_artist: Option<&'a str>,
+    date: Option<&'a str>,
+    tracks: Vec<SidecarTrack<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct SidecarTrack<'a> {
+    disc: Option<u32>,
+    track: Option<u32>,
+    title: &'a str,
+    artist: Option<&'a str>,
+    file: PathBuf,
+}
+
+/// The resolved album written by --export-album
+#[derive(Debug, Serialize)]
+struct AlbumExport<'a> {
+    album: Option<&'a str>,
+    album_artist: Option<&'a str>,
+    date: Option<&'a str>,
+    format: Format,
+    tracks: Vec<ExportTrack<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct ExportTrack<'a> {
+    file: Cow<'a, Path>,
+    disc: Option<u32>,
+    track: Option<u32>,
+    title: &'a str,
+    artist: Option<&'a str>,
+    genre: Option<&'a str>,
+    output: PathBuf,
+}
+
+/// Escape text for use in XML
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+impl Sidecar<'_> {
+    /// Format as a Kodi album NFO file
+    fn to_nfo(&self) -> String {
+        let mut nfo =
+            String::from("<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"yes\" ?>\n");
+        nfo += "<album>\n";
+        let tags = [
+            ("title", self.album),
+            ("artist", self.album_artist),
+            ("year", self.date),
+        ];
+        for (tag, value) in tags {
+            if let Some(value) = value {
+                nfo += &format!("    <{tag}>{}</{tag}>\n", xml_escape(value));
+            }
+        }
+        for (i, track) in self.tracks.iter().enumerate() {
+            nfo += "    <track>\n";
+            let position = track.track.unwrap_or(i as u32 + 1);
+            nfo += &format!("        <position>{position}</position>\n");
+            nfo += &format!("        <title>{}</title>\n", xml_escape(track.title));
+            nfo += "    </track>\n";
+        }
+        nfo += "</album>\n";
+        nfo
+    }
+}
+
+/// Which artist to use when a track has its own artist and there's also an album artist, for
+/// --artist-priority and --filename-artist
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum ArtistPriority {
+    /// The album artist if set, otherwise the track artist
+    Album,
+    /// The track artist if set, otherwise the album artist
+    Track,
+}
+
+/// Image format for embedded cover art, for --cover-format
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum CoverFormat {
+    Jpg,
+    Png,
+    /// Embed the cover as-is
+    Copy,
+}
+
+impl CoverFormat {
+    /// The ffmpeg video encoder for this format
+    fn codec(self) -> &'static str {
+        match self {
+            Self::Jpg => "mjpeg",
+            Self::Png => "png",
+            Self::Copy => "copy",
+        }
+    }
+}
+
+/// Which value wins when a track's CSV row and its --sidecar-dir file both set a field
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum SidecarPrecedence {
+    Csv,
+    Sidecar,
+}
+
+/// Letter case of output filenames for --filename-case
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum FilenameCase {
+    Lower,
+    /// Keep the case of the title and artist, after deunicode transliteration
+    Preserve,
+}
+
+/// Letter case of output file extensions for --ext-case
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ArgEnum, Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum ExtCase {
+    Lower,
+    Upper,
+    /// Keep the extension as-is
+    Preserve,
+}
+
+/// The albums to convert in one output format, each with its tracks or the error from preparing
+/// it
+type Batch = Vec<Result<(Args, Vec<Track>)>>;
+
+#[derive(Debug, Clone, Parser, Serialize)]
+#[clap(version, setting(clap::AppSettings::DeriveDisplayOrder))]
+struct Args {
+    /// Directory that input files are loacted in (default is the current directory)
+    ///
+    /// Only applies to relative paths in the CSV's file column, absolute paths are used as-is.
+    #[clap(short = 'd', long)]
+    input_dir: Option<PathBuf>,
+
+    /// If --input-dir isn't set, look up relative paths in the CSV's file column in the directory
+    /// that the CSV is in, rather than the current directory. With --albums, this is each album's
+    /// CSV.
+    #[clap(long, conflicts_with = "parse-filename")]
+    input_dir_from_csv: bool,
+
+    /// Cover art file (jpg or png image. This path is always relative to the current directory,
+    /// not the directory specified by --input-dir)
+    #[clap(short, long)]
+    cover: Option<PathBuf>,
+
+    /// Only use --cover for input files that don't already have embedded cover art. Files that do
+    /// keep their existing art. This uses ffprobe to check the input files.
+    #[clap(long, requires = "cover")]
+    cover_if_missing: bool,
+
+    /// Only embed cover art in the first track of each disc, to save space. That's the first one
+    /// listed for each disc, whatever its track number, even if --only or --skip leave it out.
+    /// With --single-file, the combined track gets the cover. Other tracks won't have any cover
+    /// art, not even art that was already in the input file. Some players only look at the first
+    /// track for album art, but many show art per track and will show nothing for the rest of the
+    /// album.
+    #[clap(long, requires = "cover")]
+    cover_first_track_only: bool,
+
+    /// Convert cover art to this image format when embedding it, e.g. 'jpg' for players that
+    /// don't support PNG covers. 'copy' embeds the image unchanged, and is the default unless
+    /// --compat is used.
+    #[clap(long, arg_enum, requires = "cover")]
+    cover_format: Option<CoverFormat>,
+
+    /// JPEG quality for covers converted with --cover-format jpg, from 1 (best) to 31 (smallest),
+    /// which is ffmpeg's -q:v. The default is 2.
+    #[clap(long, value_name = "QUALITY", parse(try_from_str = parse_cover_quality))]
+    cover_quality: Option<u32>,
+
+    /// If a track's cover art file doesn't exist, warn and convert the track without cover art
+    /// instead of failing it
+    #[clap(long)]
+    skip_missing_cover: bool,
+
+    /// If there's no --cover, look for a cover art file like cover.jpg or folder.jpg in
+    /// --input-dir and use it. Names are matched ignoring case.
+    #[clap(long)]
+    auto_cover: bool,
+
+    /// Filename for --auto-cover to look for, instead of the defaults (cover.jpg, cover.png,
+    /// folder.jpg, folder.png, front.jpg, and front.png). Can be repeated, and earlier names are
+    /// preferred.
+    #[clap(
+        long,
+        value_name = "NAME",
+        number_of_values = 1,
+        requires = "auto-cover"
+    )]
+    cover_name: Vec<String>,
+
+    /// Album Title ('album' metadata field)
+    #[clap(short = 't', long)]
+    album_title: Option<String>,
+
+    /// Album Artist ('album_artist' metadata field)
+    #[clap(short, long)]
+    album_artist: Option<String>,
+
+    /// Album date/year ('date' metadata field)
+    #[clap(short = 'y', long)]
+    date: Option<String>,
+
+    /// If --date isn't set, use the year at the start of the input directory's name as the date,
+    /// e.g. "2019 - Album Name" sets the date to 2019.
+    #[clap(long)]
+    infer_date: bool,
+
+    /// Set the disc number of tracks from the directory they're in, if it's named like "CD1",
+    /// "Disc 2", etc. Tracks that already have a disc number from the CSV or --parse-filename
+    /// keep it. With --parse-filename, audio files in these subdirectories are included too.
+    #[clap(long)]
+    disc_from_subdir: bool,
+
+    /// When every track is split from one input file using the start and end columns, convert
+    /// the whole input file into a single FLAC file instead of one file per track, with an
+    /// embedded cue sheet marking where each track starts. The cue sheet is stored in a CUESHEET
+    /// tag, which players like foobar2000 and DeaDBeeF read. The file is named and tagged after
+    /// the album rather than a track.
+    #[clap(long)]
+    single_file: bool,
+
+    /// Directory of per-track JSON metadata files, like the .info.json files written by
+    /// youtube-dl. Each track's metadata is read from the file named after its input file, e.g.
+    /// "song.json" or "song.info.json" for "song.opus". Tracks without a file are left alone.
+    ///
+    /// These keys are used, and anything else in the file is ignored:
+    ///     title               - The track's title
+    ///     artist              - The track's artist
+    ///     genre               - The track's genre
+    ///     disc, disc_number   - The disc number, as an integer
+    ///     track, track_number - The track number, as an integer
+    #[clap(long, verbatim_doc_comment)]
+    sidecar_dir: Option<PathBuf>,
+
+    /// Whether the CSV or --sidecar-dir files win when both have a value for a field
+    #[clap(long, arg_enum, default_value = "csv")]
+    sidecar_precedence: SidecarPrecedence,
+
+    /// Replace underscores in track titles and artists with spaces, e.g. "My_Song" becomes
+    /// "My Song". Runs of spaces are collapsed into one.
+    #[clap(long)]
+    unescape_titles: bool,
+
+    /// Count the tracks on each disc and the number of discs in the CSV, and write the disc and
+    /// track metadata with totals like "3/12".
+    #[clap(long)]
+    auto_totals: bool,
+
+    /// Don't add the disc/track number prefix to output filenames. Disc and track metadata is still
+    /// written to the output files.
+    #[clap(long)]
+    flat: bool,
+
+    /// Output filename template, with the fields {disc}, {track}, {artist}, and {title}, e.g.
+    /// "{track} {artist} - {title}". Track numbers are padded to two digits, and fields that a
+    /// track doesn't have are left empty. {today} and {now} work like they do in OUTPUT_DIR. If
+    /// the template ends with a file extension, like "{artist} - {title}.mp3", the output format
+    /// is chosen from it, overriding --format.
+    #[clap(long, conflicts_with = "flat")]
+    name_template: Option<FilenamePattern>,
+
+    /// When two tracks would have the same output filename, add the first 8 characters of each
+    /// input file's SHA-256 to their names, like "01-Artist-Title-3f2a9c1e.flac". The names stay
+    /// the same between runs, and only colliding input files are hashed. Tracks cut from the same
+    /// input file have the same hash, so they're still an error. Without this, a later track
+    /// overwrites an earlier one with the same name.
+    #[clap(
+        long,
+        conflicts_with_all = &["pipe", "self-test", "normalize-filenames", "single-file"]
+    )]
+    hash_on_collision: bool,
+
+    /// Write output files into a subdirectory of OUTPUT_DIR named after the album title, which is
+    /// created if needed. If the album has no title, files are written to OUTPUT_DIR directly.
+    #[clap(long)]
+    album_folder: bool,
+
+    /// Group output files into directories named after the first letter of their artist, e.g.
+    /// OUTPUT_DIR/B/ for "Beatles". Artists that don't start with a letter go in OUTPUT_DIR/#/.
+    /// The artist is the one used for filenames, chosen by --filename-artist. With
+    /// --album-folder, the album's directory goes inside the letter directory.
+    #[clap(long, conflicts_with = "normalize-filenames")]
+    alphabetize: bool,
+
+    /// Output audio format. MP3, FLAC, and Opus output are all gapless without any extra options:
+    /// ffmpeg writes the LAME/Xing header with the encoder delay and padding to MP3 files, and
+    /// Opus records the delay in its pre-skip header field. The MP3 header can only be written to
+    /// a seekable file, so MP3 output from --pipe isn't gapless.
+    #[clap(short, long, arg_enum, default_value = "flac")]
+    format: Format,
+
+    /// Also convert every track to this format, in addition to --format. Can be repeated. Each
+    /// format's files are written to a subdirectory of OUTPUT_DIR named after its extension, like
+    /// OUTPUT_DIR/flac and OUTPUT_DIR/opus. Options for a specific format, like
+    /// --compression-level or --bitrate, only apply to the formats that they're for.
+    #[clap(
+        long,
+        arg_enum,
+        value_name = "FORMAT",
+        number_of_values = 1,
+        conflicts_with_all = &["pipe", "self-test", "normalize-filenames", "rename-only", "single-file"]
+    )]
+    also_format: Vec<Format>,
+
+    /// If ffmpeg doesn't have the encoder for --format, convert tracks in this format instead.
+    /// The output file gets this format's extension, and that's the file listed in --manifest and
+    /// the report. Tracks that fail for any other reason aren't retried.
+    #[clap(
+        long,
+        arg_enum,
+        value_name = "FORMAT",
+        conflicts_with_all = &["pipe", "rename-only"]
+    )]
+    fallback_format: Option<Format>,
+
+    /// ID3v2 tag version to write for MP3 output. Some older players only support 2.3.
+    #[clap(long, arg_enum)]
+    id3_version: Option<Id3Version>,
+
+    /// Also write an ID3v1 tag for MP3 output, for very old players
+    #[clap(long)]
+    id3v1: bool,
+
+    /// FLAC compression level, from 0 (fastest) to 12 (smallest). ffmpeg's default is 5.
+    #[clap(long, value_name = "LEVEL")]
+    compression_level: Option<u32>,
+
+    /// MP3 variable bitrate quality, from 0 (best) to 9 (smallest)
+    #[clap(long, conflicts_with = "bitrate")]
+    quality: Option<u32>,
+
+    /// Audio bitrate for lossy formats, e.g. '128k'. MP3 is encoded at this constant bitrate, and
+    /// Opus uses this as its average.
+    #[clap(long, parse(try_from_str = parse_bitrate))]
+    bitrate: Option<String>,
+
+    /// Maximum audio bitrate for Opus, e.g. '96k'. This uses Opus's constrained VBR mode, which
+    /// never goes over the bitrate. For a hard limit with MP3, use --bitrate instead.
+    #[clap(long, parse(try_from_str = parse_bitrate), conflicts_with = "bitrate")]
+    max_bitrate: Option<String>,
+
+    /// Tune the Opus encoder for this kind of audio. 'voip' improves quality for spoken word.
+    #[clap(long, arg_enum)]
+    opus_application: Option<OpusApplication>,
+
+    /// Downmix surround sound (e.g. 5.1) to stereo. This can be 'dolby' for a Dolby Pro Logic II
+    /// compatible downmix, 'simple' to mix the center and rear channels into the front, or
+    /// custom arguments for ffmpeg's pan filter like "stereo|FL<FL+0.5*FC|FR<FR+0.5*FC".
+    #[clap(long)]
+    downmix_filter: Option<Downmix>,
+
+    /// Use conservative settings that work with old and finicky players: 16-bit FLAC, audio
+    /// resampled to 44.1kHz (except Opus, which is always 48kHz), cover art converted to a
+    /// baseline JPEG no larger than 600x600, ID3v2.3 tags for MP3, and no tags besides the ones
+    /// albumconv writes, i.e. --strip-all-metadata and no MusicBrainz IDs. Filenames are always
+    /// transliterated to ASCII. An explicit --bit-depth, --cover-format, or --id3-version
+    /// overrides the setting from --compat.
+    #[clap(long, conflicts_with = "rename-only")]
+    compat: bool,
+
+    /// Output sample bit depth for FLAC (default is to keep the input's bit depth)
+    #[clap(long, possible_values = ["16", "24"])]
+    bit_depth: Option<u32>,
+
+    /// Convert to 16-bit samples, i.e. CD quality. Shorthand for --bit-depth 16
+    #[clap(long)]
+    force_16bit: bool,
+
+    /// Reserve this many bytes of padding in FLAC output files, so that tags and cover art can be
+    /// edited later without rewriting the whole file. ffmpeg's default is 8192 bytes.
+    #[clap(long, value_name = "BYTES")]
+    flac_padding: Option<u32>,
+
+    /// Add a seek table to FLAC output files, with a seek point every 10 seconds. ffmpeg can't do
+    /// this itself, so this runs metaflac on each file after converting it. If metaflac isn't
+    /// installed, albumconv warns once and converts without seek tables.
+    #[clap(long)]
+    flac_seektable: bool,
+
+    /// Check that each FLAC output file decodes correctly before moving it into place, and fail
+    /// the track if it doesn't. ffmpeg's FLAC encoder has no verify option, so this runs
+    /// `flac --test` on each file after converting it, which needs the flac tool to be installed.
+    #[clap(long)]
+    flac_verify: bool,
+
+    /// Split each track's artist on this delimiter, e.g. "; ", and write a separate artist tag for
+    /// each one, which helps players group collaborations. Filenames still use the whole artist.
+    /// Only FLAC can hold multiple artist tags, and ffmpeg can't write them, so this runs
+    /// metaflac on each file after converting it. If metaflac isn't installed, albumconv warns
+    /// once and writes the whole artist as a single tag.
+    #[clap(long, value_name = "DELIMITER")]
+    split_artists: Option<String>,
+
+    /// Allow tracks with no artist (no 'artist' CSV column value and no --album-artist). These
+    /// tracks won't have any artist metadata, and their filenames will only use the title.
+    #[clap(long)]
+    allow_missing_artist: bool,
+
+    /// Which artist to tag tracks with when the CSV's 'artist' column and --album-artist are both
+    /// set. 'album' is useful for compilations whose per-track artists are wrong or inconsistent.
+    /// Either way, the other artist is used when only one is set.
+    #[clap(long, arg_enum, default_value = "track")]
+    artist_priority: ArtistPriority,
+
+    /// Which artist to use in output filenames. 'album' is useful for compilations where each
+    /// track has a different artist, so that all files sort together. 'track' uses the same
+    /// artist as the tags, following --artist-priority.
+    #[clap(long, arg_enum, default_value = "track")]
+    filename_artist: ArtistPriority,
+
+    /// Letter case of output file extensions, e.g. 'upper' writes .FLAC instead of .flac
+    #[clap(long, arg_enum, default_value = "lower")]
+    ext_case: ExtCase,
+
+    /// Letter case of output filenames, not including the extension. This only affects
+    /// filenames, the title and artist metadata keep their case.
+    #[clap(long, arg_enum, default_value = "preserve")]
+    filename_case: FilenameCase,
+
+    /// Normalize the loudness of the whole album to -18 LUFS. Every track is measured first, then
+    /// the same gain is applied to every track so that their relative volumes are preserved.
+    #[clap(long)]
+    album_normalize: bool,
+
+    /// Copy the audio stream rather than re-encoding it, only applying the output naming scheme
+    /// and metadata. Useful for re-tagging files that are already in the output format.
+    #[clap(long)]
+    rename_only: bool,
+
+    /// Skip input files that are already in the output format and already have all of the tags
+    /// that would be written, instead of re-encoding them. Nothing is written for skipped files.
+    /// This uses ffprobe to check the input files, and only compares tags, not cover art or audio
+    /// options like --album-normalize.
+    #[clap(long)]
+    skip_if_tagged: bool,
+
+    /// Check whether input files are already lossy, like MP3 or AAC, when converting to a
+    /// lossless format. Converting them only makes the files bigger without improving the audio.
+    /// 'warn' prints a warning, and 'skip' doesn't convert them. This uses ffprobe to check the
+    /// input files.
+    #[clap(long, arg_enum, conflicts_with_all = &["pipe", "rename-only"])]
+    lossy_source: Option<LossySource>,
+
+    /// Only convert these tracks, given as a list of track numbers or ranges, e.g. "3,5-7".
+    /// Tracks are numbered from 1 in the order they're listed, so the first track in a CSV is
+    /// track 1 even though it's on line 2, after the header. Error messages show both.
+    #[clap(long)]
+    only: Option<TrackIndices>,
+
+    /// Don't convert these tracks, given in the same format as --only
+    #[clap(long)]
+    skip: Option<TrackIndices>,
+
+    /// Path to the ffmpeg executable
+    #[clap(long, env = "ALBUMCONV_FFMPEG", default_value = "ffmpeg")]
+    ffmpeg: PathBuf,
+
+    /// Path to the ffprobe executable, which is used by some options to inspect input files
+    #[clap(long, env = "ALBUMCONV_FFPROBE", default_value = "ffprobe")]
+    ffprobe: PathBuf,
+
+    /// Run ffmpeg with this CPU scheduling priority (niceness), e.g. 10 to keep conversions from
+    /// slowing down other programs. On Unix this is the nice value from -20 (highest priority) to
+    /// 19 (lowest), and negative values usually require root. On Windows, any positive value
+    /// runs ffmpeg with below-normal priority and other values are ignored.
+    #[clap(long, allow_hyphen_values = true)]
+    nice: Option<i32>,
+
+    /// Read input files at playback speed, using ffmpeg's -re option, so that each ffmpeg uses
+    /// only a little CPU and disk bandwidth. This makes converting much slower, since each track
+    /// takes as long as it does to play, but is gentler for batches running in the background.
+    #[clap(long)]
+    limit_rate: bool,
+
+    /// Extra argument to pass to ffmpeg before the input file's -i, for input options like
+    /// -analyzeduration or -f. Can be repeated, with one argument each time, e.g.
+    /// --ffmpeg-input-arg=-f --ffmpeg-input-arg=s16le
+    #[clap(
+        long,
+        value_name = "ARG",
+        allow_hyphen_values = true,
+        number_of_values = 1
+    )]
+    ffmpeg_input_arg: Vec<String>,
+
+    /// Extra argument to pass to ffmpeg after all of albumconv's own output options, right before
+    /// the output file, for output options like -compression_level. Can be repeated, with one
+    /// argument each time. Since these come last, they override albumconv's options.
+    #[clap(
+        long,
+        value_name = "ARG",
+        allow_hyphen_values = true,
+        number_of_values = 1
+    )]
+    ffmpeg_output_arg: Vec<String>,
+
+    /// Directory for temporary files. Each output file is written to a temporary file first and
+    /// then renamed into place when it's complete, so that partial files never appear in the
+    /// output directory. This should be on the same filesystem as the output directory, otherwise
+    /// the rename can't be atomic. (default is the output directory itself, using hidden files)
+    #[clap(long)]
+    tempdir: Option<PathBuf>,
+
+    /// Don't start converting unless the output directory's filesystem has at least this much
+    /// free space, e.g. "500M" or "2G". This is checked again before each track, and once free
+    /// space drops below this, no new tracks are started, even with --keep-going.
+    #[clap(long, value_name = "SIZE", parse(try_from_str = parse_size))]
+    min_free: Option<u64>,
+
+    /// Set the permissions of each output file to this octal mode, e.g. 664 for group-writable
+    /// files, regardless of the umask. Only supported on Unix.
+    #[clap(long, value_name = "MODE", parse(try_from_str = parse_mode), conflicts_with = "pipe")]
+    chmod: Option<u32>,
+
+    /// Set the permissions of directories that albumconv creates for output files to this octal
+    /// mode, e.g. 2775. Directories that already exist are left alone. Only supported on Unix.
+    #[clap(long, value_name = "MODE", parse(try_from_str = parse_mode), conflicts_with = "pipe")]
+    dir_chmod: Option<u32>,
+
+    /// Number of parallel conversion tasks (default or 0 uses all CPU cores)
+    #[clap(short = 'j', long)]
+    threads: Option<usize>,
+
+    /// Maximum number of tracks being converted at once, across every album. By default this is
+    /// only limited by --threads. With --albums, threads also read and prepare albums, so a
+    /// lower limit here keeps the number of running ffmpeg processes and open files steady while
+    /// the other threads get the next albums ready.
+    #[clap(long, value_name = "N")]
+    max_inflight: Option<NonZeroUsize>,
+
+    /// Keep converting the rest of the tracks after one fails, instead of stopping. albumconv
+    /// still exits with an error if any track failed.
+    #[clap(long)]
+    keep_going: bool,
+
+    /// Append the path of each output file to this file as soon as it's finished, one per line.
+    /// Since it's written as tracks finish, it stays accurate even when a run fails or is
+    /// interrupted, and can be used with --resume.
+    #[clap(long, value_name = "FILE")]
+    manifest: Option<PathBuf>,
+
+    /// Skip tracks whose output file is listed in --manifest and still exists, to pick up where a
+    /// failed or interrupted run left off.
+    #[clap(long, requires = "manifest")]
+    resume: bool,
+
+    /// Write the SHA-256 checksum of each output file to this file, in the format used by
+    /// sha256sum, so the files can be checked later with 'sha256sum -c'. Like --manifest, lines
+    /// are appended to the file, so a --resume run keeps the checksums from earlier runs.
+    #[clap(long, value_name = "FILE")]
+    checksums: Option<PathBuf>,
+
+    /// How to write output file paths in --manifest, --checksums, and --sidecar files. Relative
+    /// paths are relative to the directory of the file they're written in, so that the files can
+    /// be moved together. Absolute paths are better for indexing.
+    #[clap(long, arg_enum, default_value = "relative")]
+    path_style: PathStyle,
+
+    /// Expand environment variables like $VAR or ${VAR} in the --input-dir, --cover, and output
+    /// directory paths. Useful when paths come from somewhere that isn't expanded by a shell.
+    #[clap(long)]
+    expand_env: bool,
+
+    /// Treat INPUT_CSV as a directory containing one CSV file per album, and convert all of them.
+    /// Each album is written to a subdirectory of OUTPUT_DIR named after its CSV file.
+    #[clap(long)]
+    albums: bool,
+
+    /// CSV file with album metadata for --albums, so that each album can get its own settings.
+    ///
+    /// The CSV should have these columns:
+    ///     name            - The album's CSV filename without the .csv extension
+    ///     album           - The album title
+    ///     album_artist    - The album artist
+    ///     date            - The album's release date
+    ///     cover           - Path to the album's cover art
+    ///
+    /// Every column except name is optional. Command-line options like --album-title take
+    /// precedence over this file, which takes precedence over comments in the album's CSV.
+    #[clap(long, value_name = "CSV")]
+    batch_metadata: Option<PathBuf>,
+
+    /// Instead of reading a CSV file, treat INPUT_CSV as a directory of audio files and parse each
+    /// file's metadata from its name using this pattern. The pattern can contain the fields
+    /// {artist}, {title}, {disc}, and {track}, e.g. "{track} - {artist} - {title}". The file
+    /// extension isn't part of the pattern.
+    #[clap(long, conflicts_with_all = &["albums", "input-dir"])]
+    parse_filename: Option<FilenamePattern>,
+
+    /// Instead of reading a CSV file, treat INPUT_CSV as a list of audio files, one per line.
+    /// Tracks are numbered in the order they're listed, and each title is the file's name without
+    /// its extension. Relative paths are relative to --input-dir.
+    #[clap(long, conflicts_with_all = &["albums", "parse-filename"])]
+    file_list: bool,
+
+    /// Convert a single track read from standard input, and write the result to standard output.
+    /// The CSV file and output directory aren't used, all metadata comes from command line options.
+    #[clap(long, conflicts_with_all = &["input-csv", "output-dir"])]
+    pipe: bool,
+
+    /// Check that ffmpeg works by converting a generated test tone with the given options, and
+    /// verifying that the output can be decoded and has the right metadata.
+    #[clap(long, conflicts_with_all = &["input-csv", "output-dir", "pipe"])]
+    self_test: bool,
+
+    /// Print a shell completion script to stdout and exit
+    #[clap(long, value_parser, hide = true, value_name = "SHELL")]
+    #[serde(skip)]
+    completion: Option<Shell>,
+
+    /// Track title when using --pipe
+    #[clap(long)]
+    title: Option<String>,
+
+    /// Track artist when using --pipe (default is the album artist)
+    #[clap(long)]
+    artist: Option<String>,
+
+    /// Number of tracks on each disc, computed by --auto-totals
+    #[clap(skip)]
+    #[serde(skip)]
+    track_totals: HashMap<Option<u32>, u32>,
+
+    /// Number of discs, computed by --auto-totals
+    #[clap(skip)]
+    #[serde(skip)]
+    disc_total: Option<u32>,
+
+    /// Gain applied to every track, computed by --album-normalize
+    #[clap(skip)]
+    #[serde(skip)]
+    album_gain: Option<f64>,
+
+    /// Total duration of the album in seconds, computed by --tag-album-duration
+    #[clap(skip)]
+    #[serde(skip)]
+    album_duration: Option<f64>,
+
+    /// When albumconv started, for {today} and {now}
+    #[clap(skip)]
+    #[serde(skip)]
+    started: StartTime,
+
+    /// Lowercase genre aliases and their canonical names, read from --genre-map
+    #[clap(skip)]
+    #[serde(skip)]
+    genres: HashMap<String, String>,
+
+    /// File extension given at the end of --name-template
+    #[clap(skip)]
+    template_extension: Option<String>,
+
+    /// Cue sheet to embed for --single-file
+    #[clap(skip)]
+    #[serde(skip)]
+    cuesheet: Option<String>,
+
+    /// The open --manifest file, shared by every thread
+    #[clap(skip)]
+    #[serde(skip)]
+    manifest_file: Option<Arc<Mutex<std::fs::File>>>,
+
+    /// The open --checksums file, shared by every thread
+    #[clap(skip)]
+    #[serde(skip)]
+    checksums_file: Option<Arc<Mutex<std::fs::File>>>,
+
+    /// The --max-inflight limit, shared by every album
+    #[clap(skip)]
+    #[serde(skip)]
+    inflight: Option<Arc<Semaphore>>,
+
+    /// Number of tracks in the album before --only and --skip, counted by prepare_album
+    #[clap(skip)]
+    #[serde(skip)]
+    listed_tracks: usize,
+
+    /// Output formats that ffmpeg doesn't have an encoder for, found by check_encoder
+    #[clap(skip)]
+    #[serde(skip)]
+    missing_encoders: Vec<Format>,
+
+    /// Output files listed in --manifest when it was opened, for --resume
+    #[clap(skip)]
+    #[serde(skip)]
+    finished: HashSet<PathBuf>,
+
+    /// Runs the ffmpeg commands that convert tracks
+    #[clap(skip = Arc::new(CommandRunner) as Arc<dyn Runner>)]
+    #[serde(skip)]
+    runner: Arc<dyn Runner>,
+
+    /// Treat warnings as errors. This affects:
+    ///     - Unknown keys in CSV comment lines
+    ///     - --tempdir being on a different filesystem than the output directory
+    ///     - --infer-date not finding a year
+    ///     - --batch-metadata rows that don't match any album CSV
+    ///     - metaflac not being installed for --flac-seektable or --split-artists
+    ///     - A cover for an output format that can't hold cover art
+    ///     - Files without a title, or whose new name is taken, for --normalize-filenames
+    ///     - --album-folder for an album without a title
+    ///     - Missing cover art files, with --skip-missing-cover
+    ///     - Album CSVs with no tracks
+    ///     - Tracks converted in --fallback-format, or a missing encoder for --format with one
+    ///     - Lossy input files, with --lossy-source warn
+    #[clap(long, verbatim_doc_comment)]
+    strict: bool,
+
+    /// Include a summary of each track's artist, title, disc, and track number in the output line
+    /// printed when it's converted.
+    #[clap(long)]
+    show_tags: bool,
+
+    /// Print a table of each track's input file, disc and track numbers, artist, title, and
+    /// output file, then exit without converting anything. Artists that come from
+    /// --album-artist are marked as such.
+    #[clap(long, conflicts_with_all = &["pipe", "self-test"])]
+    list_tracks: bool,
+
+    /// Before converting the first track, print its ffmpeg command with an explanation of why
+    /// each argument is there. With --verbose, every track's command is explained.
+    #[clap(long, conflicts_with = "pipe")]
+    explain: bool,
+
+    /// Instead of converting anything, rename the audio files in this directory to match
+    /// albumconv's naming scheme, using the tags already in each file. Naming options like
+    /// --name-template and --filename-case apply. Use --dry-run to see the changes first.
+    #[clap(
+        long,
+        value_name = "DIR",
+        conflicts_with_all = &["pipe", "self-test", "albums", "list-tracks", "input-csv"]
+    )]
+    normalize_filenames: Option<PathBuf>,
+
+    /// Probe every track's duration with ffprobe before converting, and write the album's total
+    /// duration to each track as an ALBUM_DURATION tag, formatted as [H:]MM:SS. Like
+    /// --auto-totals, this counts the whole album, even with --only or --skip.
+    #[clap(long, conflicts_with = "pipe")]
+    tag_album_duration: bool,
+
+    /// Don't convert or rename anything. With --normalize-filenames, print what would be renamed.
+    /// Otherwise, print a rough estimate of each output file's size and the total, based on the
+    /// input's duration and audio parameters from ffprobe and a typical bitrate for the output
+    /// format. Estimates for FLAC vary the most, since it depends on the audio.
+    #[clap(long, conflicts_with_all = &["pipe", "self-test", "list-tracks"])]
+    dry_run: bool,
+
+    /// Don't convert anything. Instead, compare each track's output file, if it already exists,
+    /// with what would be written, and print whether it's new, unchanged, would only be re-tagged,
+    /// or would be re-encoded because it's in a different format. This uses ffprobe, and like
+    /// --skip-if-tagged, only compares the format and tags, not cover art or audio options.
+    #[clap(long, conflicts_with_all = &["pipe", "self-test", "list-tracks", "dry-run"])]
+    compare_existing: bool,
+
+    /// Don't ask for confirmation. Otherwise, albumconv asks before converting more than 100
+    /// tracks or overwriting existing output files, when stdin is a terminal.
+    #[clap(long)]
+    assume_yes: bool,
+
+    /// Print ffmpeg's output for every track, even when it succeeds. This is useful for spotting
+    /// warnings from ffmpeg. Each line is prefixed with the input filename.
+    #[clap(long)]
+    show_ffmpeg_output: bool,
+
+    /// Print each track's progress while ffmpeg converts it, every 10% of the track's duration.
+    /// This is most useful for albums with a few very long tracks. Needs ffprobe to find each
+    /// track's duration.
+    #[clap(long, conflicts_with_all = &["pipe", "rename-only"])]
+    progress: bool,
+
+    /// ffmpeg's log level when converting tracks. This controls how much ffmpeg prints on failure
+    /// or with --verbose/--show-ffmpeg-output, use 'info' to get ffmpeg's usual output.
+    #[clap(long, arg_enum, default_value = "error")]
+    ffmpeg_loglevel: FfmpegLogLevel,
+
+    /// Fail a track if ffmpeg logs any warnings or errors while converting it, even if ffmpeg
+    /// succeeds. ffmpeg's log level is raised to at least 'warning', and each message is tagged
+    /// with its level so that warnings can be told apart from other output.
+    #[clap(long, conflicts_with = "pipe")]
+    halt_on_warning: bool,
+
+    /// Don't fail tracks for ffmpeg warnings that contain this text, ignoring case. Can be used
+    /// multiple times.
+    #[clap(
+        long,
+        value_name = "PATTERN",
+        multiple_occurrences = true,
+        requires = "halt-on-warning"
+    )]
+    ignore_warning: Vec<String>,
+
+    /// When interrupted with Ctrl-C, print how many tracks have finished so far, then wait for
+    /// tracks that are already running to finish without starting any new ones. A second Ctrl-C
+    /// stops the running tracks and exits immediately. Only supported on Unix.
+    #[clap(long)]
+    summary_on_sigint: bool,
+
+    /// Color status messages. Color is always disabled with --report-format json.
+    #[clap(long, arg_enum, default_value = "auto")]
+    color: ColorChoice,
+
+    /// Format of the report printed at the end of the run.
+    ///
+    /// 'text' prints a line for each converted track and a final line with counts. 'json' prints
+    /// only a single JSON object when all tracks are finished, with this schema:
+    ///     {
+    ///       "converted": <number of tracks converted>,
+    ///       "skipped": <number of tracks skipped>,
+    ///       "failed": <number of tracks that failed>,
+    ///       "tracks": [
+    ///         {
+    ///           "file": <input file from the CSV>,
+    ///           "output": <output file path, or null if it couldn't be determined>,
+    ///           "outcome": "converted" | "skipped" | "failed",
+    ///           "error": <error message if the track failed, otherwise null>
+    ///         },
+    ///         ...
+    ///       ]
+    ///     }
+    #[clap(long, arg_enum, default_value = "text", verbatim_doc_comment)]
+    report_format: ReportFormat,
+
+    /// File mapping genre names to canonical ones, so that e.g. "hip-hop" and "Hip Hop" in the
+    /// CSV's genre column are both written as "Hip-Hop". Names are matched ignoring case, and
+    /// genres that aren't in the map are written unchanged.
+    ///
+    /// A .json file should contain an object mapping names to canonical names. Any other file is
+    /// read as a CSV with 'alias' and 'genre' columns.
+    #[clap(long, value_name = "FILE")]
+    genre_map: Option<PathBuf>,
+
+    /// Extra tag to write to every track, as KEY=VALUE, e.g. --meta "comment=Ripped from vinyl".
+    /// Can be repeated. If a key is given more than once, the last value is used, and these
+    /// override albumconv's own tags with the same key.
+    #[clap(long, value_name = "KEY=VALUE", parse(try_from_str = parse_meta))]
+    meta: Vec<(String, String)>,
+
+    /// Don't copy any tags or chapters from the input file, so the output only has the tags that
+    /// albumconv writes from the CSV and options. By default, ffmpeg copies the input's tags, and
+    /// albumconv's tags replace any with the same name.
+    #[clap(long)]
+    strip_all_metadata: bool,
+
+    /// Unicode normalization for tags written to output files. Text from different sources can
+    /// write the same accented character in different ways, which makes library managers show
+    /// duplicate-looking artists and albums. This doesn't affect filenames.
+    #[clap(long, arg_enum, default_value = "nfc")]
+    unicode_normalize: UnicodeForm,
+
+    /// After converting, write an album metadata file to the output directory with the album
+    /// title, artist, date, and track list. 'json' writes album.json, and 'nfo' writes a
+    /// Kodi-style album.nfo.
+    #[clap(long, arg_enum)]
+    sidecar: Option<SidecarFormat>,
+
+    /// Before converting, write the resolved album to this TOML file: the album settings, and
+    /// each track's input file, tags, and output file, after artist fallbacks, genre mapping,
+    /// and --only/--skip are applied. This is a record of exactly what albumconv converts.
+    #[clap(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = &["pipe", "self-test", "normalize-filenames", "albums", "also-format"]
+    )]
+    export_album: Option<PathBuf>,
+
+    /// Print the options that albumconv would use as JSON and exit, after applying defaults,
+    /// environment variables like ALBUMCONV_FFMPEG, and shorthand options like --force-16bit.
+    /// This is useful for debugging how options interact.
+    #[clap(long)]
+    dump_config: bool,
+
+    /// Show verbose output, including which ffmpeg commands are run and their live output
+    #[clap(short, long)]
+    verbose: bool,
+
+    /// CSV file containing track information
+    ///
+    /// The input CSV should contain these columns:
+    ///     file    - The input filename, relative to the directory specified by --input-dir.
+    ///               Absolute paths are used as-is, even when --input-dir is set.
+    ///     disc    - The disc number for this track
+    ///     track   - The track number for this track
+    ///     position    - The disc and track number together, as DISC.TRACK like "1.03", or just
+    ///                   TRACK. This fills in the disc and track columns if they're empty.
+    ///     title   - The track's title
+    ///     artist  - The track's artist
+    ///     genre   - The track's genre
+    ///     compression - FLAC compression level for this track, overriding --compression-level
+    ///     quality     - MP3 VBR quality for this track, overriding --quality
+    ///     cover   - Cover art for this track, overriding --cover. Relative paths are looked up in
+    ///               --input-dir, like the file column.
+    ///     start   - Start time within the input file, as [[HH:]MM:]SS[.sss] or seconds
+    ///     end     - End time within the input file, in the same format as start
+    ///     musicbrainz_trackid     - MusicBrainz recording ID
+    ///     musicbrainz_albumid     - MusicBrainz release ID
+    ///     musicbrainz_artistid    - MusicBrainz artist ID
+    ///
+    /// The disc and track columns are optional, or individual rows can have an empty value for
+    /// those columns. In this case, no disc or track metadata field will be added to the output
+    /// file.
+    ///
+    /// The start and end columns are also optional, and allow splitting one long input file into
+    /// several tracks by having multiple rows with the same file. By default the whole input file
+    /// is converted. The genre, compression, quality, and musicbrainz columns are optional, and
+    /// the musicbrainz columns are written as the corresponding MUSICBRAINZ_* tags when present.
+    ///
+    /// If the CSV has no tracks, nothing is converted and albumconv exits with status 2.
+    ///
+    /// Before the header row, the CSV may contain comment lines of the form '# key: value' to set
+    /// album-wide defaults. Options given on the command line take precedence. Supported keys:
+    ///     album           - Same as --album-title
+    ///     album_artist    - Same as --album-artist
+    ///     date            - Same as --date
+    ///     cover           - Same as --cover
+    #[clap(
+        verbatim_doc_comment,
+        required_unless_present_any = &["pipe", "self-test", "normalize-filenames", "completion", "dump-config"]
+    )]
+    input_csv: Option<PathBuf>,
+
+    /// Directory in which to write output files. {today} is replaced with the date that albumconv
+    /// started, like "2024-06-01", and {now} with the date and time, like "2024-06-01T15-30-00".
+    #[clap(required_unless_present_any = &["pipe", "self-test", "normalize-filenames", "completion", "dump-config"])]
+    output_dir: Option<PathBuf>,
+}
+
+fn maybe_metadata<T: Display>(key: &str, val: &Option<T>) -> String {
+    match val {
+        Some(ref val) => format!("{key}={val}"),
+        None => String::new(),
+    }
+}
+
+/// Replace underscores with spaces for --unescape-titles, and collapse repeated spaces
+fn unescape_underscores(s: &str) -> String {
+    s.replace('_', " ")
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Format a disc or track number, adding the total as "N/M" if it's known
+fn with_total(num: Option<u32>, total: Option<u32>) -> Option<String> {
+    match (num, total) {
+        (Some(num), Some(total)) => Some(format!("{num}/{total}")),
+        (Some(num), None) => Some(num.to_string()),
+        (None, _) => None,
+    }
+}
+
+fn expand_env_path(path: &Path) -> Result<PathBuf> {
+    let s = path
+        .to_str()
+        .ok_or_else(|| anyhow!("path '{}' is not valid UTF-8", path.display()))?;
+    let expanded = shellexpand::env(s)
+        .with_context(|| format!("failed to expand environment variables in path '{s}'"))?;
+    Ok(PathBuf::from(expanded.as_ref()))
+}
+
+/// Add input options to only read the part of the input file between the track's start and end
+fn add_input_range(cmd: &mut ExplainedCommand, track: &Track) {
+    if let Some(start) = track.start {
+        cmd.args(
+            "start at the track's start time",
+            ["-ss", &start.to_string()],
+        );
+    }
+    if let Some(end) = track.end {
+        cmd.args("stop at the track's end time", ["-to", &end.to_string()]);
+    }
+}
+
+/// A command being built along with the reason for each group of arguments, for --explain
+struct ExplainedCommand {
+    cmd: Command,
+    notes: Vec<(Vec<OsString>, String)>,
+}
+
+impl ExplainedCommand {
+    fn new(cmd: Command) -> Self {
+        Self {
+            cmd,
+            notes: Vec::new(),
+        }
+    }
+
+    /// Add arguments to the command, and the reason for adding them
+    fn args<S: AsRef<OsStr>>(&mut self, why: &str, args: impl IntoIterator<Item = S>) {
+        let args = args
+            .into_iter()
+            .map(|arg| arg.as_ref().to_owned())
+            .collect::<Vec<_>>();
+        if !args.is_empty() {
+            self.cmd.args(&args);
+            self.notes.push((args, why.to_owned()));
+        }
+    }
+
+    /// Describe the command, one group of arguments per line with the reason for each
+    fn explain(&self, file: &Path) -> String {
+        let lines = self
+            .notes
+            .iter()
+            .map(|(args, why)| {
+                let args = args
+                    .iter()
+                    .map(|arg| format!("{arg:?}"))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                (args, why)
+            })
+            .collect::<Vec<_>>();
+        // line up the explanations, but don't let one long argument push them all off the screen
+        let width = lines
+            .iter()
+            .map(|(args, _)| args.chars().count())
+            .filter(|&len| len <= 60)
+            .max();
+        let mut text = format!(
+            "ffmpeg command for {}:\n  {:?}\n",
+            file.display(),
+            self.cmd.get_program()
+        );
+        for (args, why) in lines {
+            text += &format!("  {args:width$}  # {why}\n", width = width.unwrap_or(0));
+        }
+        text
+    }
+}
+
+/// Run a command and capture its output. If `stream_prefix` is set, also print each line of the
+/// command's stderr as it runs, prefixed by `[stream_prefix]`. If `on_stdout` is set, it's called
+/// with each line of the command's stdout as it runs.
+fn run_command(
+    cmd: &mut Command,
+    stream_prefix: Option<&str>,
+    on_stdout: Option<&(dyn Fn(&str) + Sync)>,
+) -> io::Result<Output> {
+    #[cfg(unix)]
+    let separate_group = SEPARATE_PROCESS_GROUPS.load(Ordering::Relaxed);
+    #[cfg(unix)]
+    if separate_group {
+        use std::os::unix::process::CommandExt;
+        cmd.process_group(0);
+    }
+    let mut child = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    #[cfg(unix)]
+    let _running = separate_group.then(|| RunningChild::new(child.id()));
+    if stream_prefix.is_none() && on_stdout.is_none() {
+        return child.wait_with_output();
+    }
+
+    std::thread::scope(|scope| {
+        // read stdout in another thread so that the child can't block on a full pipe
+        let stdout_pipe = child.stdout.take().expect("child stdout is piped");
+        let stdout_thread = scope.spawn(move || -> io::Result<Vec<u8>> {
+            let mut reader = BufReader::new(stdout_pipe);
+            let mut buf = Vec::new();
+            let Some(on_stdout) = on_stdout else {
+                reader.read_to_end(&mut buf)?;
+                return Ok(buf);
+            };
+            loop {
+                let start = buf.len();
+                if reader.read_until(b'\n', &mut buf)? == 0 {
+                    return Ok(buf);
+                }
+                on_stdout(String::from_utf8_lossy(&buf[start..]).trim_end());
+            }
+        });
+
+        // ffmpeg uses carriage returns to update its progress line, so treat those as line breaks
+        // too
+        let mut stderr = Vec::new();
+        let mut line = Vec::new();
+        let stderr_pipe = child.stderr.take().expect("child stderr is piped");
+        for byte in BufReader::new(stderr_pipe).bytes() {
+            let byte = byte?;
+            stderr.push(byte);
+            let Some(prefix) = stream_prefix else {
+                continue;
+            };
+            if byte == b'\n' || byte == b'\r' {
+                if !line.is_empty() {
+                    println!("[{prefix}] {}", String::from_utf8_lossy(&line));
+                    line.clear();
+                }
+            } else {
+                line.push(byte);
+            }
+        }
+        if let (Some(prefix), false) = (stream_prefix, line.is_empty()) {
+            println!("[{prefix}] {}", String::from_utf8_lossy(&line));
+        }
+
+        let status = child.wait()?;
+        let stdout = stdout_thread
+            .join()
+            .expect("stdout reader thread panicked")?;
+        Ok(Output {
+            status,
+            stdout,
+            stderr,
+        })
+    })
+}
+
+/// Runs external commands. Every ffmpeg, ffprobe, flac, and metaflac command goes through this
+/// rather than being run directly, so that a fake runner can check the commands that would be run
+/// without needing the tools to be installed. The only exception is --pipe, whose ffmpeg reads
+/// and writes albumconv's own stdin and stdout.
+trait Runner: std::fmt::Debug + Send + Sync {
+    /// Run a command and capture its output, like run_command
+    fn run(
+        &self,
+        cmd: &mut Command,
+        stream_prefix: Option<&str>,
+        on_stdout: Option<&(dyn Fn(&str) + Sync)>,
+    ) -> io::Result<Output>;
+
+    /// Run a command and capture its output, like Command::output
+    fn output(&self, cmd: &mut Command) -> io::Result<Output> {
+        self.run(cmd, None, None)
+    }
+}
+
+/// The Runner that actually runs commands
+#[derive(Debug)]
+struct CommandRunner;
+
+impl Runner for CommandRunner {
+    fn run(
+        &self,
+        cmd: &mut Command,
+        stream_prefix: Option<&str>,
+        on_stdout: Option<&(dyn Fn(&str) + Sync)>,
+    ) -> io::Result<Output> {
+        run_command(cmd, stream_prefix, on_stdout)
+    }
+}
+
+impl Args {
+    /// Apply album defaults from '# key: value' comment lines at the start of the CSV, for options
+    /// that weren't set on the command line. Returns the rest of the CSV after the comments.
+    fn apply_csv_comments<'a>(&mut self, mut csv: &'a str) -> Result<&'a str> {
+        while let Some(comment) = csv.strip_prefix('#') {
+            let (line, rest) = comment.split_once('\n').unwrap_or((comment, ""));
+            csv = rest;
+
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            if value.is_empty() {
+                continue;
+            }
+            let field = match key.trim() {
+                "album" => &mut self.album_title,
+                "album_artist" => &mut self.album_artist,
+                "date" => &mut self.date,
+                "cover" => {
+                    self.cover.get_or_insert_with(|| PathBuf::from(value));
+                    continue;
+                }
+                key => {
+                    self.warn(format_args!("unknown CSV comment key '{key}'"))?;
+                    continue;
+                }
+            };
+            field.get_or_insert_with(|| value.to_owned());
+        }
+        Ok(csv)
+    }
+
+    /// Print a warning, or return it as an error in --strict mode
+    fn warn(&self, msg: impl Display) -> Result<()> {
+        if self.strict {
+            bail!("{msg} (warnings are errors with --strict)");
+        }
+        eprintln!("{} {msg}", Style::Yellow.paint("Warning:"));
+        Ok(())
+    }
+
+    /// Check for conflicting options and resolve shorthand options into their full forms.
+    fn validate(&mut self) -> Result<()> {
+        if let Some(ext) = self
+            .name_template
+            .as_mut()
+            .and_then(FilenamePattern::take_extension)
+        {
+            self.format = Format::from_extension(&ext).ok_or_else(|| {
+                anyhow!("unknown output file extension '.{ext}' in --name-template")
+            })?;
+            self.template_extension = Some(ext);
+            if !self.also_format.is_empty() {
+                bail!("--name-template can't choose the output format when using --also-format");
+            }
+        }
+        let mut formats = self.formats();
+        formats.extend(self.fallback_format);
+        if self
+            .parse_filename
+            .as_ref()
+            .is_some_and(FilenamePattern::has_time_fields)
+        {
+            bail!("{{today}} and {{now}} can't be used in --parse-filename");
+        }
+        if self.force_16bit {
+            match self.bit_depth {
+                Some(16) | None => self.bit_depth = Some(16),
+                Some(depth) => bail!("--force-16bit conflicts with --bit-depth {depth}"),
+            }
+        }
+        if !formats.contains(&Format::Mp3) && (self.id3_version.is_some() || self.id3v1) {
+            bail!("--id3-version and --id3v1 can only be used with MP3 output");
+        }
+        if self.compat {
+            if formats.contains(&Format::Flac) && self.bit_depth.is_none() {
+                self.bit_depth = Some(16);
+            }
+            if formats.contains(&Format::Mp3) && self.id3_version.is_none() {
+                self.id3_version = Some(Id3Version::V2_3);
+            }
+            self.strip_all_metadata = true;
+        }
+        if self.min_free.is_some() && self.pipe {
+            bail!("--min-free can't be used with --pipe");
+        }
+        if (self.chmod.is_some() || self.dir_chmod.is_some()) && !cfg!(unix) {
+            self.warn("--chmod and --dir-chmod are only supported on Unix")?;
+            self.chmod = None;
+            self.dir_chmod = None;
+        }
+        if (self.manifest.is_some() || self.checksums.is_some()) && self.pipe {
+            bail!("--manifest and --checksums can't be used with --pipe");
+        }
+        if self.skip_if_tagged && self.pipe {
+            bail!("--skip-if-tagged can't be used with --pipe");
+        }
+        if self.cover_if_missing && self.pipe {
+            bail!("--cover-if-missing can't be used with --pipe");
+        }
+        if let Some(level) = self.compression_level {
+            check_compression(&formats, level)?;
+        }
+        if let Some(quality) = self.quality {
+            check_quality(&formats, quality)?;
+        }
+        if self.rename_only && (self.compression_level.is_some() || self.quality.is_some()) {
+            bail!("--rename-only can't change compression or quality because it doesn't re-encode");
+        }
+        let lossless = formats.iter().all(|format| format.lossless());
+        if lossless && (self.bitrate.is_some() || self.max_bitrate.is_some()) {
+            bail!("--bitrate and --max-bitrate can only be used with lossy output formats");
+        }
+        if !formats.contains(&Format::Opus) && self.max_bitrate.is_some() {
+            bail!(
+                "the MP3 encoder doesn't support --max-bitrate, use --bitrate for constant bitrate"
+            );
+        }
+        if self.rename_only && (self.bitrate.is_some() || self.max_bitrate.is_some()) {
+            bail!("--rename-only can't change the bitrate because it doesn't re-encode audio");
+        }
+        if !formats.contains(&Format::Opus) && self.opus_application.is_some() {
+            bail!("--opus-application can only be used with Opus output");
+        }
+        let flac = formats.contains(&Format::Flac);
+        if !flac && (self.flac_padding.is_some() || self.flac_seektable) {
+            bail!("--flac-padding and --flac-seektable can only be used with FLAC output");
+        }
+        if self.split_artists.is_some() && (!flac || self.pipe) {
+            bail!("--split-artists only works with FLAC output, and can't be used with --pipe");
+        }
+        if self.single_file && (self.format != Format::Flac || self.pipe) {
+            bail!("--single-file only works with FLAC output, and can't be used with --pipe");
+        }
+        if self.flac_verify && (!flac || self.pipe) {
+            bail!("--flac-verify only works with FLAC output, and can't be used with --pipe");
+        }
+        if self.flac_seektable && self.pipe {
+            bail!("--flac-seektable needs a seekable file, so it can't be used with --pipe");
+        }
+        if !flac && self.bit_depth.is_some() {
+            bail!("--bit-depth and --force-16bit can only be used with FLAC output");
+        }
+        if !self.pipe && (self.title.is_some() || self.artist.is_some()) {
+            bail!("--title and --artist can only be used with --pipe");
+        }
+        if self.album_normalize && (self.rename_only || self.pipe) {
+            bail!("--album-normalize can't be used with --rename-only or --pipe");
+        }
+        if self.batch_metadata.is_some() && !self.albums {
+            bail!("--batch-metadata can only be used with --albums");
+        }
+        if self.rename_only && self.downmix_filter.is_some() {
+            bail!("--rename-only can't downmix because it doesn't re-encode audio");
+        }
+        if self.cover_quality.is_some() && self.cover_format() != CoverFormat::Jpg {
+            bail!("--cover-quality can only be used with --cover-format jpg");
+        }
+        if self.rename_only && self.bit_depth.is_some() {
+            bail!("--rename-only can't change the bit depth because it doesn't re-encode audio");
+        }
+        Ok(())
+    }
+
+    fn expand_env_vars(&mut self) -> Result<()> {
+        if let Some(dir) = &self.input_dir {
+            self.input_dir = Some(expand_env_path(dir)?);
+        }
+        if let Some(cover) = &self.cover {
+            self.cover = Some(expand_env_path(cover)?);
+        }
+        if let Some(dir) = &self.output_dir {
+            self.output_dir = Some(expand_env_path(dir)?);
+        }
+        Ok(())
+    }
+
+    /// Merge a track's --sidecar-dir JSON file into it, if there is one
+    fn apply_track_sidecar(&self, dir: &Path, track: &mut Track) -> Result<()> {
+        let Some(stem) = track.file.file_stem() else {
+            return Ok(());
+        };
+        let candidates = [".json", ".info.json"].map(|ext| {
+            let mut name = stem.to_owned();
+            name.push(ext);
+            dir.join(name)
+        });
+        let Some(path) = candidates.iter().find(|path| path.is_file()) else {
+            return Ok(());
+        };
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to open {}", path.display()))?;
+        let json: serde_json::Value = serde_json::from_str(&text)
+            .with_context(|| format!("failed to parse {}", path.display()))?;
+
+        let string = |key: &str| json[key].as_str().map(str::to_owned);
+        let number = |keys: [&str; 2]| {
+            keys.iter()
+                .find_map(|key| json[key].as_u64())
+                .and_then(|n| u32::try_from(n).ok())
+        };
+        let sidecar_wins = self.sidecar_precedence == SidecarPrecedence::Sidecar;
+        fn merge<T>(field: &mut Option<T>, value: Option<T>, overwrite: bool) {
+            if value.is_some() && (overwrite || field.is_none()) {
+                *field = value;
+            }
+        }
+
+        if let Some(title) = string("title") {
+            if sidecar_wins || track.title.is_empty() {
+                track.title = title;
+            }
+        }
+        merge(&mut track.artist, string("artist"), sidecar_wins);
+        merge(&mut track.genre, string("genre"), sidecar_wins);
+        merge(
+            &mut track.disc,
+            number(["disc", "disc_number"]),
+            sidecar_wins,
+        );
+        merge(
+            &mut track.track,
+            number(["track", "track_number"]),
+            sidecar_wins,
+        );
+        Ok(())
+    }
+
+    /// Open --manifest for appending, and read the output files it already lists for --resume
+    fn open_manifest(&mut self, path: &Path) -> Result<()> {
+        if self.resume {
+            match std::fs::read_to_string(path) {
+                Ok(text) => self.finished = text.lines().map(PathBuf::from).collect(),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+                Err(err) => {
+                    return Err(err).with_context(|| format!("failed to read {}", path.display()))
+                }
+            }
+        }
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("failed to open {}", path.display()))?;
+        self.manifest_file = Some(Arc::new(Mutex::new(file)));
+        Ok(())
+    }
+
+    /// Record a finished output file in --manifest
+    fn append_manifest(&self, output_file: &Path) -> Result<()> {
+        let (Some(file), Some(path)) = (&self.manifest_file, &self.manifest) else {
+            return Ok(());
+        };
+        let line = self.listed_path(path, output_file);
+        // The file isn't buffered, so each line is written out immediately
+        let mut file = file.lock().expect("manifest lock poisoned");
+        writeln!(file, "{}", line.display()).context("failed to write to manifest")
+    }
+
+    /// Whether an output file was finished by a previous run, for --resume
+    fn already_finished(&self, output_file: &Path) -> bool {
+        match &self.manifest {
+            Some(path) if self.resume && output_file.exists() => {
+                self.finished.contains(&self.listed_path(path, output_file))
+            }
+            _ => false,
+        }
+    }
+
+    /// How to write the path of an output file in a generated file like --manifest, following
+    /// --path-style. The output file has to exist, otherwise its path is used as-is.
+    fn listed_path(&self, listing: &Path, output_file: &Path) -> PathBuf {
+        let Ok(file) = output_file.canonicalize() else {
+            return output_file.to_owned();
+        };
+        let dir = match listing.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        match (self.path_style, dir.canonicalize()) {
+            (PathStyle::Relative, Ok(dir)) => relative_path(&dir, &file),
+            _ => file,
+        }
+    }
+
+    /// Hash a finished output file and add it to --checksums
+    fn append_checksum(&self, output_file: &Path) -> Result<()> {
+        let (Some(file), Some(path)) = (&self.checksums_file, &self.checksums) else {
+            return Ok(());
+        };
+        let hash = sha256_file(output_file)?;
+        let name = self.listed_path(path, output_file);
+        let mut file = file.lock().expect("checksums lock poisoned");
+        writeln!(file, "{hash}  {}", name.display()).context("failed to write to checksums file")
+    }
+
+    /// Read the --genre-map file
+    fn load_genre_map(&mut self, path: &Path) -> Result<()> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to open {}", path.display()))?;
+        let map: HashMap<String, String> = if path.extension().is_some_and(|ext| ext == "json") {
+            serde_json::from_str(&text)
+                .with_context(|| format!("failed to parse {}", path.display()))?
+        } else {
+            #[derive(Deserialize)]
+            struct Row {
+                alias: String,
+                genre: String,
+            }
+            csv::ReaderBuilder::new()
+                .trim(csv::Trim::All)
+                .from_reader(text.as_bytes())
+                .deserialize()
+                .map(|row| row.map(|row: Row| (row.alias, row.genre)))
+                .collect::<Result<_, _>>()
+                .with_context(|| format!("failed to parse {}", path.display()))?
+        };
+        self.genres = map
+            .into_iter()
+            .map(|(alias, genre)| (alias.to_lowercase(), genre))
+            .collect();
+        Ok(())
+    }
+
+    /// The format to convert cover art to, which is JPEG by default with --compat
+    fn cover_format(&self) -> CoverFormat {
+        match self.cover_format {
+            Some(format) => format,
+            None if self.compat => CoverFormat::Jpg,
+            None => CoverFormat::Copy,
+        }
+    }
+
+    /// Map a genre to its canonical name using --genre-map
+    fn genre<'a>(&'a self, genre: &'a str) -> &'a str {
+        self.genres
+            .get(&genre.to_lowercase())
+            .map_or(genre, String::as_str)
+    }
+
+    /// Create a new ffmpeg command, running at the priority set by --nice
+    fn ffmpeg(&self) -> Command {
+        let mut cmd = Command::new(&self.ffmpeg);
+        if let Some(nice) = self.nice {
+            #[cfg(unix)]
+            {
+                use std::os::unix::process::CommandExt;
+                // SAFETY: setpriority is async-signal-safe, so it's fine to call between fork and
+                // exec.
+                unsafe {
+                    cmd.pre_exec(move || {
+                        if libc::setpriority(libc::PRIO_PROCESS, 0, nice) == 0 {
+                            Ok(())
+                        } else {
+                            Err(io::Error::last_os_error())
+                        }
+                    });
+                }
+            }
+            #[cfg(windows)]
+            {
+                use std::os::windows::process::CommandExt;
+                const BELOW_NORMAL_PRIORITY_CLASS: u32 = 0x4000;
+                if nice > 0 {
+                    cmd.creation_flags(BELOW_NORMAL_PRIORITY_CLASS);
+                }
+            }
+        }
+        cmd
+    }
+
+    /// Describe a track for error messages, with its number for --only and --skip, and the line
+    /// of the CSV that it came from, like "track 3 (b.wav, line 4 of album.csv)". The --single-file
+    /// track isn't numbered, so it's described by its title instead.
+    fn track_location(&self, track: &Track) -> String {
+        let mut details = Vec::new();
+        if !track.file.as_os_str().is_empty() {
+            details.push(track.file.display().to_string());
+        }
+        if let (Some(row), Some(csv_file)) = (track.row, &self.input_csv) {
+            details.push(format!("line {row} of {}", csv_file.display()));
+        }
+        let name = match track.number {
+            0 => format!("'{}'", track.title),
+            number => format!("track {number}"),
+        };
+        match details.is_empty() {
+            true => name,
+            false => format!("{name} ({})", details.join(", ")),
+        }
+    }
+
+    /// Resolve the artist for a track from its own artist and the album artist, in the order set
+    /// by --artist-priority. The artist can only be None with --allow-missing-artist.
+    fn track_artist<'a>(&'a self, track: &'a Track) -> Result<Option<&'a str>> {
+        let (track_artist, album_artist) = (track.artist.as_deref(), self.album_artist.as_deref());
+        let artist = match self.artist_priority {
+            ArtistPriority::Track => track_artist.or(album_artist),
+            ArtistPriority::Album => album_artist.or(track_artist),
+        };
+        if artist.is_none() && !self.allow_missing_artist {
+            bail!("unable to determine the artist, fill in the 'artist' CSV column or use --album-artist");
+        }
+        Ok(artist)
+    }
+
+    /// Create a new ffprobe command
+    fn ffprobe(&self) -> Command {
+        Command::new(&self.ffprobe)
+    }
+
+    /// Whether any of the enabled options need ffprobe
+    fn needs_ffprobe(&self) -> bool {
+        self.cover_if_missing
+            || self.skip_if_tagged
+            || self.lossy_source.is_some()
+            || self.normalize_filenames.is_some()
+            || self.dry_run
+            || self.tag_album_duration
+            || self.compare_existing
+            || self.progress
+    }
+
+    /// Make sure that ffprobe can be run, so that options which need it don't fail on every track
+    fn check_ffprobe(&self) -> Result<()> {
+        let output = self
+            .runner
+            .output(self.ffprobe().arg("-version"))
+            .with_context(|| format!("failed to run {}", self.ffprobe.display()))?;
+        if !output.status.success() {
+            bail!("{} -version failed", self.ffprobe.display());
+        }
+        Ok(())
+    }
+
+    /// Check that ffmpeg has an encoder for the output format. Builds without libmp3lame or
+    /// libopus are common, and this gives a clearer error than ffmpeg failing on every track.
+    /// With --fallback-format, a missing encoder for the other formats is only a warning, since
+    /// their tracks will be converted in the fallback format instead.
+    fn check_encoder(&mut self) -> Result<()> {
+        let mut cmd = self.ffmpeg();
+        cmd.args(["-hide_banner", "-encoders"]);
+        let output = self
+            .runner
+            .output(&mut cmd)
+            .with_context(|| format!("failed to execute {cmd:?}"))?;
+        let encoders = String::from_utf8_lossy(&output.stdout);
+        // Encoders are listed like " A....D flac    FLAC (Free Lossless Audio Codec)"
+        let has_encoder = |format: Format| {
+            encoders
+                .lines()
+                .any(|line| line.split_whitespace().nth(1) == Some(format.codec()))
+        };
+        for format in self.formats().into_iter().chain(self.fallback_format) {
+            let codec = format.codec();
+            if has_encoder(format) {
+                continue;
+            }
+            if let Some(fallback) = self.fallback_format.filter(|&f| f != format) {
+                self.warn(format_args!(
+                    "{} doesn't have the {codec} encoder, {} output will be converted to {} \
+                     instead",
+                    self.ffmpeg.display(),
+                    format.extension(),
+                    fallback.extension()
+                ))?;
+                self.missing_encoders.push(format);
+            } else {
+                bail!(
+                    "{} doesn't have the {codec} encoder, which is needed for {} output",
+                    self.ffmpeg.display(),
+                    format.extension()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Options for retrying a track in --fallback-format, unless it's already the output format
+    fn fallback(&self) -> Option<Args> {
+        let format = self
+            .fallback_format
+            .filter(|&format| format != self.format)?;
+        let mut args = self.clone();
+        args.format = format;
+        args.fallback_format = None;
+        args.template_extension = None;
+        Some(args)
+    }
+
+    /// The output file that a track was converted to, which is in --fallback-format if only that
+    /// one exists
+    fn converted_output(&self, track: &Track) -> Result<PathBuf> {
+        let output_file = self.output_file(track)?;
+        if !output_file.exists() {
+            if let Some(fallback) = self.fallback() {
+                let fallback_file = fallback.output_file(track)?;
+                if fallback_file.exists() {
+                    return Ok(fallback_file);
+                }
+            }
+        }
+        Ok(output_file)
+    }
+
+    /// Every output format, from --format and --also-format
+    fn formats(&self) -> Vec<Format> {
+        let mut formats = vec![self.format];
+        for &format in &self.also_format {
+            if !formats.contains(&format) {
+                formats.push(format);
+            }
+        }
+        formats
+    }
+
+    /// Options for converting to one of the formats from --also-format, which writes to its own
+    /// subdirectory of the output directory. --also-format is kept so that checks like
+    /// check_compression still know about every format.
+    fn for_format(&self, format: Format) -> Args {
+        let mut args = self.clone();
+        args.format = format;
+        args.output_dir = Some(self.output_dir().join(format.extension()));
+        args
+    }
+
+    /// Convert the album, or every album with --albums. With --also-format, this converts to each
+    /// format in turn. Every album is checked in every format before converting any of them, so
+    /// that there's one prompt for the whole batch.
+    fn convert_all(&mut self) -> Result<Summary> {
+        let formats = match self.also_format.is_empty() {
+            true => vec![self.clone()],
+            false => self
+                .formats()
+                .into_iter()
+                .map(|format| self.for_format(format))
+                .collect(),
+        };
+        let batches = formats
+            .into_iter()
+            .map(|args| args.prepare_batch())
+            .collect::<Result<Vec<_>>>()?;
+        let ready = batches
+            .iter()
+            .flatten()
+            .filter_map(|res| res.as_ref().ok())
+            .map(|(album, tracks)| (album, tracks))
+            .collect::<Vec<_>>();
+        self.confirm_batch(&ready)?;
+
+        let mut summary = Summary::default();
+        for batch in batches {
+            summary.merge(self.convert_batch(batch)?);
+            let stop = summary.error.is_some() && !self.keep_going;
+            if stop || INTERRUPTED.load(Ordering::Relaxed) {
+                break;
+            }
+        }
+        Ok(summary)
+    }
+
+    /// Check that the flac tool can be run for --flac-verify. Unlike metaflac, this is an error
+    /// rather than a warning, since skipping verification would defeat the point of asking for it.
+    fn check_flac(&self) -> Result<()> {
+        let output = self
+            .runner
+            .output(Command::new("flac").arg("--version"))
+            .context("failed to run flac, which is needed for --flac-verify")?;
+        if !output.status.success() {
+            bail!("flac --version failed");
+        }
+        Ok(())
+    }
+
+    /// Decode a FLAC file with `flac --test` for --flac-verify, which also checks the audio
+    /// against the MD5 signature that the encoder stored in the file
+    fn verify_flac(&self, file: &Path) -> Result<()> {
+        let mut cmd = Command::new("flac");
+        cmd.args(["--test", "--silent"]).arg(file);
+        if self.verbose {
+            println!("+ {cmd:?}");
+        }
+        let output = self
+            .runner
+            .output(&mut cmd)
+            .with_context(|| format!("failed to execute {cmd:?}"))?;
+        if !output.status.success() {
+            bail!(
+                "verification of {} failed: {}",
+                file.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(())
+    }
+
+    /// Check that metaflac can be run for --flac-seektable, and turn off seek tables if it can't
+    fn check_metaflac(&mut self) -> Result<()> {
+        let ok = self
+            .runner
+            .output(Command::new("metaflac").arg("--version"))
+            .is_ok_and(|output| output.status.success());
+        if !ok {
+            self.warn(
+                "metaflac wasn't found, --flac-seektable and --split-artists will be ignored",
+            )?;
+            self.flac_seektable = false;
+            self.split_artists = None;
+        }
+        Ok(())
+    }
+
+    /// Whether any of the enabled options need metaflac
+    fn needs_metaflac(&self) -> bool {
+        self.flac_seektable || self.split_artists.is_some()
+    }
+
+    /// Split an artist into multiple artists for --split-artists
+    fn split_artists<'a>(&self, artist: &'a str) -> Vec<&'a str> {
+        match &self.split_artists {
+            Some(delim) => artist
+                .split(delim.as_str())
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .collect(),
+            None => vec![artist],
+        }
+    }
+
+    /// Edit a finished FLAC file with metaflac, to add a seek table for --flac-seektable and
+    /// replace the artist tag with one tag per artist for --split-artists
+    fn run_metaflac(&self, file: &Path, artist: Option<&str>) -> Result<()> {
+        let mut cmd = Command::new("metaflac");
+        if self.flac_seektable {
+            cmd.arg("--add-seekpoint=10s");
+        }
+        let artists = artist.map(|a| self.split_artists(a)).unwrap_or_default();
+        if artists.len() > 1 {
+            cmd.arg("--remove-tag=ARTIST");
+            for artist in artists {
+                let artist = self.unicode_normalize.apply(artist);
+                cmd.arg(format!("--set-tag=ARTIST={artist}"));
+            }
+        }
+        if cmd.get_args().len() == 0 {
+            return Ok(());
+        }
+        cmd.arg(file);
+        if self.verbose {
+            println!("+ {cmd:?}");
+        }
+        let output = self
+            .runner
+            .output(&mut cmd)
+            .with_context(|| format!("failed to execute {cmd:?}"))?;
+        if !output.status.success() {
+            bail!(
+                "failed to run metaflac on {}: {}",
+                file.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(())
+    }
+
+    /// Fail if the output directory's filesystem has less free space than --min-free
+    fn check_free_space(&self) -> Result<()> {
+        let Some(min_free) = self.min_free else {
+            return Ok(());
+        };
+        let free = free_space(self.output_dir())?;
+        if free < min_free {
+            bail!(
+                "only {} free for {}, which is less than --min-free {}",
+                format_size(free),
+                self.output_dir().display(),
+                format_size(min_free),
+            );
+        }
+        Ok(())
+    }
+
+    /// Check whether an input file has embedded cover art, using ffprobe
+    fn has_embedded_cover(&self, input_file: &Path) -> Result<bool> {
+        let mut cmd = self.ffprobe();
+        cmd.args(["-v", "error", "-select_streams", "v"]);
+        cmd.args(["-show_entries", "stream=index", "-of", "csv=p=0"]);
+        cmd.arg(input_file);
+        if self.verbose {
+            println!("+ {cmd:?}");
+        }
+        let output = self
+            .runner
+            .output(&mut cmd)
+            .with_context(|| format!("failed to execute {cmd:?}"))?;
+        if !output.status.success() {
+            bail!(
+                "failed to probe {}: {}",
+                input_file.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(!output.stdout.iter().all(u8::is_ascii_whitespace))
+    }
+
+    /// Decide which cover art to embed for a track
+    fn cover_art<'a>(&'a self, track: &'a Track, input_file: &Path) -> Result<CoverArt<'a>> {
+        match track.cover.as_deref().or(self.cover.as_deref()) {
+            None => Ok(CoverArt::None),
+            Some(_) if !self.format.supports_cover() => Ok(CoverArt::None),
+            Some(_) if self.cover_first_track_only && !track.first_on_disc => Ok(CoverArt::None),
+            Some(_) if self.cover_if_missing && self.has_embedded_cover(input_file)? => {
+                Ok(CoverArt::Embedded)
+            }
+            Some(cover) if !cover.exists() => {
+                if !self.skip_missing_cover {
+                    bail!("cover art {} doesn't exist", cover.display());
+                }
+                self.warn(format_args!(
+                    "cover art {} doesn't exist, converting {} without it",
+                    cover.display(),
+                    input_file.display()
+                ))?;
+                Ok(CoverArt::None)
+            }
+            Some(cover) => Ok(CoverArt::File(cover)),
+        }
+    }
+
+    /// The metadata tags to write for a track, as "key=value" strings. The order is always the
+    /// same, so that ffmpeg commands are reproducible: albumconv's own tags in a fixed order,
+    /// followed by --meta tags sorted by key.
+    fn metadata(&self, track: &Track, artist: Option<&str>) -> Vec<String> {
+        let metadata = [
+            format!("title={}", track.title),
+            maybe_metadata("artist", &artist),
+            maybe_metadata("genre", &track.genre.as_deref().map(|g| self.genre(g))),
+            maybe_metadata("album", &self.album_title),
+            maybe_metadata("album_artist", &self.album_artist),
+            maybe_metadata("date", &self.date),
+            maybe_metadata("disc", &with_total(track.disc, self.disc_total)),
+            maybe_metadata(
+                "track",
+                &with_total(track.track, self.track_totals.get(&track.disc).copied()),
+            ),
+            maybe_metadata("ALBUM_DURATION", &self.album_duration.map(format_duration)),
+            maybe_metadata("MUSICBRAINZ_TRACKID", &track.musicbrainz_trackid),
+            maybe_metadata("MUSICBRAINZ_ALBUMID", &track.musicbrainz_albumid),
+            maybe_metadata("MUSICBRAINZ_ARTISTID", &track.musicbrainz_artistid),
+        ];
+        let custom = self.meta.iter().cloned().collect::<BTreeMap<_, _>>();
+        metadata
+            .into_iter()
+            .filter(|s| !s.is_empty())
+            .filter(|s| !(self.compat && s.starts_with("MUSICBRAINZ_")))
+            .chain(custom.iter().map(|(key, value)| format!("{key}={value}")))
+            .map(|m| self.unicode_normalize.apply(&m))
+            .collect()
+    }
+
+    /// Check whether an input file is already in the output format and has all of the metadata
+    /// that would be written to it, for --skip-if-tagged
+    fn is_already_tagged(
+        &self,
+        codec: Option<&str>,
+        tags: &HashMap<String, String>,
+        track: &Track,
+        artist: Option<&str>,
+    ) -> bool {
+        codec == Some(self.format.probe_codec()) && self.has_metadata(tags, track, artist)
+    }
+
+    /// Check whether probed tags include all of the metadata that would be written for a track
+    fn has_metadata(
+        &self,
+        tags: &HashMap<String, String>,
+        track: &Track,
+        artist: Option<&str>,
+    ) -> bool {
+        self.metadata(track, artist).iter().all(|m| {
+            let (key, value) = m.split_once('=').expect("metadata is key=value");
+            tags.get(&key.to_lowercase()).is_some_and(|v| v == value)
+        })
+    }
+
+    /// Decide what converting a track would do to its existing output file, for
+    /// --compare-existing
+    fn disposition(&self, track: &Track) -> Result<Disposition> {
+        let output_file = self.output_file(track)?;
+        if !output_file.exists() {
+            return Ok(Disposition::New);
+        }
+        let (codec, tags) = self.probe_tags(&output_file)?;
+        if codec.as_deref() != Some(self.format.probe_codec()) {
+            Ok(Disposition::Reencode)
+        } else if self.has_metadata(&tags, track, self.track_artist(track)?) {
+            Ok(Disposition::Unchanged)
+        } else {
+            Ok(Disposition::Retag)
+        }
+    }
+
+    /// Read the audio codec and tags of a file using ffprobe. Tag names are lowercased, since
+    /// containers differ in how they store them.
+    fn probe_tags(&self, input_file: &Path) -> Result<(Option<String>, HashMap<String, String>)> {
+        let mut cmd = self.ffprobe();
+        cmd.args(["-v", "error", "-select_streams", "a:0", "-of", "json"]);
+        cmd.args(["-show_entries", "format_tags:stream=codec_name:stream_tags"]);
+        cmd.arg(input_file);
+        if self.verbose {
+            println!("+ {cmd:?}");
+        }
+        let output = self
+            .runner
+            .output(&mut cmd)
+            .with_context(|| format!("failed to execute {cmd:?}"))?;
+        if !output.status.success() {
+            bail!(
+                "failed to probe {}: {}",
+                input_file.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        let probe: serde_json::Value =
+            serde_json::from_slice(&output.stdout).with_context(|| {
+                format!(
+                    "failed to parse ffprobe output for {}",
+                    input_file.display()
+                )
+            })?;
+
+        let stream = &probe["streams"][0];
+        let codec = stream["codec_name"].as_str().map(str::to_owned);
+
+        // Ogg keeps tags on the stream rather than the container, so look in both places
+        let mut tags = HashMap::new();
+        for source in [&probe["format"]["tags"], &stream["tags"]] {
+            if let Some(source) = source.as_object() {
+                for (key, value) in source {
+                    if let Some(value) = value.as_str() {
+                        tags.insert(key.to_lowercase(), value.to_owned());
+                    }
+                }
+            }
+        }
+        Ok((codec, tags))
+    }
+
+    /// Build the ffmpeg command to convert a track, not including the output filename. Each group
+    /// of arguments is added with the reason for it, for --explain.
+    fn ffmpeg_command(
+        &self,
+        track: &Track,
+        artist: Option<&str>,
+        input_file: &Path,
+        cover: CoverArt,
+    ) -> ExplainedCommand {
+        let mut cmd = ExplainedCommand::new(self.ffmpeg());
+        if self.halt_on_warning {
+            let level = match self.ffmpeg_loglevel {
+                FfmpegLogLevel::Quiet | FfmpegLogLevel::Error => FfmpegLogLevel::Warning,
+                level => level,
+            };
+            cmd.args(
+                "quieter output, but log warnings tagged with their level for --halt-on-warning, \
+                 and don't read stdin",
+                [
+                    "-hide_banner",
+                    "-loglevel",
+                    &format!("level+{}", level.as_str()),
+                    "-nostdin",
+                ],
+            );
+        } else {
+            cmd.args(
+                "quieter output, with the log level from --ffmpeg-loglevel, and don't read stdin",
+                [
+                    "-hide_banner",
+                    "-loglevel",
+                    self.ffmpeg_loglevel.as_str(),
+                    "-nostdin",
+                ],
+            );
+        }
+        add_input_range(&mut cmd, track);
+        if self.limit_rate {
+            cmd.args(
+                "read the input at playback speed, for --limit-rate",
+                ["-re"],
+            );
+        }
+        cmd.args(
+            "input options from --ffmpeg-input-arg",
+            &self.ffmpeg_input_arg,
+        );
+        cmd.args("the input file", [OsStr::new("-i"), input_file.as_os_str()]);
+        match cover {
+            CoverArt::None => {
+                cmd.args("only use the audio, not any cover art", ["-map", "0:a"]);
+            }
+            CoverArt::File(cover) => {
+                cmd.args(
+                    "cover art from --cover or the CSV",
+                    [OsStr::new("-i"), cover.as_os_str()],
+                );
+                cmd.args(
+                    "use the audio from the input file and the picture from the cover",
+                    ["-map", "0:a", "-map", "1:v"],
+                );
+            }
+            CoverArt::Embedded => {
+                cmd.args(
+                    "keep the input file's own cover art, for --cover-if-missing",
+                    ["-map", "0:a", "-map", "0:v:0"],
+                );
+            }
+        }
+
+        if self.strip_all_metadata {
+            cmd.args(
+                "don't copy tags or chapters from the input, for --strip-all-metadata",
+                ["-map_metadata", "-1", "-map_chapters", "-1"],
+            );
+        }
+        for m in self.metadata(track, artist) {
+            let key = m.split_once('=').map_or(m.as_str(), |(key, _)| key);
+            let why = match key {
+                "title" | "artist" | "genre" => "tag from the CSV",
+                "album" => "tag from --album-title",
+                "album_artist" => "tag from --album-artist",
+                "date" => "tag from --date",
+                "disc" | "track" => "number from the CSV, with the total from --auto-totals",
+                "ALBUM_DURATION" => "total album duration from --tag-album-duration",
+                key if key.starts_with("MUSICBRAINZ_") => "MusicBrainz ID from the CSV",
+                _ => "tag from --meta",
+            };
+            cmd.args(why, ["-metadata", &m]);
+        }
+
+        if cover != CoverArt::None {
+            cmd.args(
+                "store the picture as cover art, converted by --cover-format",
+                [
+                    "-c:v",
+                    self.cover_format().codec(),
+                    "-disposition:v",
+                    "attached_pic",
+                    "-metadata:s:v",
+                    "comment=Cover (front)",
+                ],
+            );
+            if self.cover_format() == CoverFormat::Jpg {
+                let quality = self.cover_quality.unwrap_or(2);
+                cmd.args(
+                    "JPEG quality for the cover from --cover-quality",
+                    ["-q:v", &quality.to_string()],
+                );
+                if self.compat {
+                    let size = COMPAT_COVER_SIZE;
+                    cmd.args(
+                        "shrink the cover and use baseline 4:2:0 JPEG for --compat",
+                        [
+                            "-filter:v",
+                            &format!(
+                                "scale='min({size},iw)':'min({size},ih)'\
+                                 :force_original_aspect_ratio=decrease"
+                            ),
+                            "-pix_fmt",
+                            "yuvj420p",
+                        ],
+                    );
+                }
+            }
+        }
+        if self.rename_only {
+            cmd.args(
+                "copy the audio without re-encoding, for --rename-only",
+                ["-c:a", "copy"],
+            );
+        } else {
+            cmd.args(
+                "the audio encoder for --format",
+                ["-c:a", self.format.codec()],
+            );
+        }
+        let mut filters = Vec::new();
+        if let Some(downmix) = &self.downmix_filter {
+            filters.push(downmix.filter());
+        }
+        if let Some(gain) = self.album_gain {
+            filters.push(format!("volume={gain:.2}dB"));
+        }
+        if !filters.is_empty() {
+            cmd.args(
+                "audio filters for --downmix-filter and --album-normalize",
+                ["-af", &filters.join(",")],
+            );
+        }
+        if self.compat && self.format != Format::Opus {
+            cmd.args("44.1kHz sample rate for --compat", ["-ar", "44100"]);
+        }
+        match self.bit_depth.filter(|_| self.format == Format::Flac) {
+            Some(16) => {
+                cmd.args("16-bit samples for --bit-depth", ["-sample_fmt", "s16"]);
+            }
+            Some(24) => {
+                // ffmpeg's flac encoder stores 24-bit audio as 32-bit samples
+                cmd.args(
+                    "24-bit samples for --bit-depth, which ffmpeg stores as 32 bits",
+                    ["-sample_fmt", "s32", "-bits_per_raw_sample", "24"],
+                );
+            }
+            _ => (),
+        }
+        if let Some(version) = self.id3_version.filter(|_| self.format == Format::Mp3) {
+            let version = match version {
+                Id3Version::V2_3 => "3",
+                Id3Version::V2_4 => "4",
+            };
+            cmd.args(
+                "ID3 tag version from --id3-version",
+                ["-id3v2_version", version],
+            );
+        }
+        if self.id3v1 && self.format == Format::Mp3 {
+            cmd.args(
+                "also write an ID3v1 tag, for --id3v1",
+                ["-write_id3v1", "1"],
+            );
+        }
+        let compression = track.compression.or(self.compression_level);
+        if let Some(level) = compression.filter(|_| self.format == Format::Flac) {
+            cmd.args(
+                "FLAC compression level from the CSV or --compression-level",
+                ["-compression_level", &level.to_string()],
+            );
+        }
+        if let Some(bitrate) = self.bitrate.as_ref().filter(|_| !self.format.lossless()) {
+            cmd.args("bitrate from --bitrate", ["-b:a", bitrate]);
+        }
+        // a track's quality overrides --bitrate, since ffmpeg ignores the bitrate in VBR mode
+        let quality = track.quality.or(self.quality);
+        if let Some(quality) = quality.filter(|_| self.format == Format::Mp3) {
+            cmd.args(
+                "MP3 VBR quality from the CSV or --quality",
+                ["-q:a", &quality.to_string()],
+            );
+        }
+        if let Some(bitrate) = self
+            .max_bitrate
+            .as_ref()
+            .filter(|_| self.format == Format::Opus)
+        {
+            cmd.args(
+                "constrained VBR so the bitrate never goes over --max-bitrate",
+                ["-b:a", bitrate, "-vbr", "constrained"],
+            );
+        }
+        if let Some(application) = self
+            .opus_application
+            .filter(|_| self.format == Format::Opus)
+        {
+            let application = match application {
+                OpusApplication::Audio => "audio",
+                OpusApplication::Voip => "voip",
+                OpusApplication::Lowdelay => "lowdelay",
+            };
+            cmd.args(
+                "Opus encoder tuning from --opus-application",
+                ["-application", application],
+            );
+        }
+        if let Some(padding) = self.flac_padding.filter(|_| self.format == Format::Flac) {
+            cmd.args(
+                "FLAC padding from --flac-padding",
+                ["-metadata_header_padding", &padding.to_string()],
+            );
+        }
+        if let Some(cue) = &self.cuesheet {
+            cmd.args(
+                "cue sheet with where each track starts, for --single-file",
+                ["-metadata", &format!("CUESHEET={cue}")],
+            );
+        }
+        cmd.args(
+            "output options from --ffmpeg-output-arg",
+            &self.ffmpeg_output_arg,
+        );
+        cmd.args("overwrite the output file if it exists", ["-y"]);
+        cmd
+    }
+
+    fn input_file<'a>(&self, track: &'a Track) -> Cow<'a, Path> {
+        self.input_path(&track.file)
+    }
+
+    /// Look up a path from the CSV in --input-dir
+    fn input_path<'a>(&self, file: &'a Path) -> Cow<'a, Path> {
+        // Re-assemble the path from its components so that it uses the platform's preferred
+        // separator. On Windows, this turns "disc1/track.wav" from the CSV into
+        // "disc1\track.wav" so that it doesn't end up with mixed separators when joined with
+        // --input-dir. On Unix this is a no-op, since backslash isn't a separator there.
+        let file = if cfg!(windows) {
+            Cow::Owned(file.components().collect())
+        } else {
+            Cow::Borrowed(file)
+        };
+        // Absolute paths in the CSV are used as-is, only relative paths are looked up in
+        // --input-dir. (Path::join would do this anyway, but be explicit about it.)
+        match &self.input_dir {
+            Some(dir) if file.is_relative() => Cow::Owned(dir.join(file)),
+            _ => file,
+        }
+    }
+
+    /// Measure a track's integrated loudness and duration using ffmpeg's ebur128 filter
+    fn measure_loudness(&self, track: &Track) -> Result<Loudness> {
+        let mut cmd = ExplainedCommand::new(self.ffmpeg());
+        cmd.cmd.args(["-hide_banner", "-nostdin"]);
+        add_input_range(&mut cmd, track);
+        let mut cmd = cmd.cmd;
+        cmd.arg("-i");
+        cmd.arg(&*self.input_file(track));
+        cmd.args(["-map", "0:a", "-af", "ebur128", "-f", "null", "-"]);
+        if self.verbose {
+            println!("+ {cmd:?}");
+        }
+
+        let output = self
+            .runner
+            .output(&mut cmd)
+            .with_context(|| format!("failed to execute {cmd:?}"))?;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if !output.status.success() {
+            bail!(
+                "failed to measure loudness of {}: ffmpeg command failed\n\n{stderr}",
+                track.file.display()
+            );
+        }
+
+        // The duration comes from the input info, e.g. "  Duration: 00:03:21.12, start: ...", and
+        // the integrated loudness from the ebur128 summary at the end, e.g. "    I: -19.6 LUFS"
+        let mut duration = None;
+        let mut integrated = None;
+        for line in stderr.lines().map(str::trim) {
+            if let Some(rest) = line.strip_prefix("Duration:") {
+                duration = rest
+                    .split(',')
+                    .next()
+                    .and_then(|d| parse_duration(d.trim()));
+            } else if let Some(rest) = line.strip_prefix("I:") {
+                integrated = rest.trim().trim_end_matches("LUFS").trim().parse().ok();
+            }
+        }
+        // The reported duration is for the whole input file, trim it to the track's range
+        let duration = duration.map(|d| {
+            let end = track.end.map_or(d, |end| end.0.min(d));
+            let start = track.start.map_or(0.0, |start| start.0);
+            (end - start).max(0.0)
+        });
+        match (integrated, duration) {
+            (Some(integrated), Some(duration)) => Ok(Loudness {
+                integrated,
+                duration,
+            }),
+            _ => bail!(
+                "failed to measure loudness of {}: couldn't parse ffmpeg output",
+                track.file.display()
+            ),
+        }
+    }
+
+    /// Determine the output filename for a track
+    fn output_file(&self, track: &Track) -> Result<PathBuf> {
+        let artist = self.track_artist(track)?;
+        let prefix = match (track.disc, track.track) {
+            _ if self.flat => String::new(),
+            (Some(disc), Some(track)) => format!("{disc}.{track:02}-"),
+            (Some(disc), None) => format!("{disc}-"),
+            (None, Some(track)) => format!("{track:02}-"),
+            (None, None) => String::new(),
+        };
+        let filename_artist = match self.filename_artist {
+            ArtistPriority::Album => self.album_artist.as_deref().or(artist),
+            ArtistPriority::Track => artist,
+        };
+        let title = deunicode(&track.title);
+        let ext = self
+            .template_extension
+            .as_deref()
+            .unwrap_or(self.format.extension());
+        let ext = match self.ext_case {
+            ExtCase::Lower => ext.to_lowercase(),
+            ExtCase::Upper => ext.to_uppercase(),
+            ExtCase::Preserve => ext.to_owned(),
+        };
+        let stem = match (&self.name_template, filename_artist) {
+            (Some(template), _) => template.format(|field| match field {
+                FilenameField::Artist => filename_artist.map(deunicode).unwrap_or_default(),
+                FilenameField::Title => title.clone(),
+                FilenameField::Disc => track.disc.map(|n| n.to_string()).unwrap_or_default(),
+                FilenameField::Track => track.track.map(|n| format!("{n:02}")).unwrap_or_default(),
+                FilenameField::Today => self.started.today(),
+                FilenameField::Now => self.started.now(),
+            }),
+            (None, Some(artist)) => format!("{prefix}{}-{title}", deunicode(artist)),
+            (None, None) => format!("{prefix}{title}"),
+        };
+        let stem = match self.filename_case {
+            FilenameCase::Lower => stem.to_lowercase(),
+            FilenameCase::Preserve => stem,
+        };
+        let stem = match &track.name_hash {
+            Some(hash) => format!("{stem}-{hash}"),
+            None => stem,
+        };
+        Ok(self
+            .album_dir(filename_artist)
+            .join(format!("{stem}.{ext}")))
+    }
+
+    /// Directory that a track's output file is written to, which is OUTPUT_DIR, plus the
+    /// --alphabetize letter directory for the track's filename artist, plus the --album-folder
+    /// subdirectory. Path separators in the album title are replaced so it's a single directory.
+    fn album_dir(&self, artist: Option<&str>) -> PathBuf {
+        let mut dir = self.output_dir().to_owned();
+        if self.alphabetize {
+            // Tracks without an artist go in the same directory as artists that start with a
+            // number or symbol
+            let letter = artist
+                .and_then(|artist| deunicode(artist).trim_start().chars().next())
+                .filter(char::is_ascii_alphabetic)
+                .map_or('#', |c| c.to_ascii_uppercase());
+            dir.push(letter.to_string());
+        }
+        match &self.album_title {
+            Some(title) if self.album_folder => {
+                let name = deunicode(title).replace(['/', '\\'], "-");
+                match name.trim() {
+                    "" | "." | ".." => dir,
+                    name => dir.join(name),
+                }
+            }
+            _ => dir,
+        }
+    }
+
+    /// Create an output directory and its parents if needed, and set the permissions of the ones
+    /// that didn't exist yet for --dir-chmod
+    fn create_dir(&self, dir: &Path) -> Result<()> {
+        let missing = dir
+            .ancestors()
+            .take_while(|dir| !dir.as_os_str().is_empty() && !dir.is_dir())
+            .collect::<Vec<_>>();
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create {}", dir.display()))?;
+        if let Some(mode) = self.dir_chmod {
+            for dir in missing {
+                set_mode(dir, mode)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Temporary file that ffmpeg writes to, before it gets renamed to the output file. This keeps
+    /// the same extension as the output file so that ffmpeg can still detect the output format.
+    /// Each call gets a new name, since with --tempdir and --albums, tracks from different albums
+    /// can have the same output filename.
+    fn temp_file(&self, output_file: &Path) -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let dir = match &self.tempdir {
+            Some(dir) => dir.as_path(),
+            None => output_file.parent().unwrap_or(Path::new(".")),
+        };
+        let n = COUNTER.fetch_add(1, Ordering::Relaxed);
+        let mut name = OsString::from(format!(".albumconv-{}-{n}-", std::process::id()));
+        name.push(output_file.file_name().expect("output file has a name"));
+        dir.join(name)
+    }
+
+    /// Atomically rename a finished temp file to its final name. If the temp file is on a
+    /// different filesystem, fall back to copying it, which isn't atomic.
+    fn move_into_place(&self, temp_file: &Path, output_file: &Path) -> Result<()> {
+        let res = match std::fs::rename(temp_file, output_file) {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == io::ErrorKind::CrossesDevices => self
+                .warn(format_args!(
+                    "--tempdir is on a different filesystem than the output directory, {} won't \
+                     be written atomically",
+                    output_file.display()
+                ))
+                .and_then(|()| {
+                    std::fs::copy(temp_file, output_file)?;
+                    Ok(())
+                }),
+            Err(err) => Err(err.into()),
+        };
+        let _ = std::fs::remove_file(temp_file);
+        res.with_context(|| {
+            format!(
+                "failed to move {} to {}",
+                temp_file.display(),
+                output_file.display()
+            )
+        })
+    }
+
+    fn convert_track(&self, track: &Track) -> Result<Outcome> {
+        let input_file = self.input_file(track);
+        let artist = self.track_artist(track)?;
+        let output_file = self.output_file(track)?;
+
+        if self.already_finished(&output_file) {
+            if self.report_format == ReportFormat::Text {
+                let skipped = Style::Yellow.paint("Skipped:");
+                println!("{skipped} {} was already converted", output_file.display());
+            }
+            return Ok(Outcome::Skipped);
+        }
+
+        // probe the input once for both --skip-if-tagged and --lossy-source
+        let lossy_source = self.lossy_source.filter(|_| self.format.lossless());
+        let (codec, tags) = if self.skip_if_tagged || lossy_source.is_some() {
+            self.probe_tags(&input_file)?
+        } else {
+            Default::default()
+        };
+        if self.skip_if_tagged && self.is_already_tagged(codec.as_deref(), &tags, track, artist) {
+            if self.report_format == ReportFormat::Text {
+                let skipped = Style::Yellow.paint("Skipped:");
+                println!("{skipped} {} is already tagged", input_file.display());
+            }
+            return Ok(Outcome::Skipped);
+        }
+        if let (Some(lossy_source), Some(codec)) = (lossy_source, &codec) {
+            if LOSSY_CODECS.contains(&codec.as_str()) {
+                let format = self.format.extension();
+                match lossy_source {
+                    LossySource::Warn => self.warn(format_args!(
+                        "{} is lossy {codec} audio, converting it to {format} won't improve it",
+                        input_file.display()
+                    ))?,
+                    LossySource::Skip => {
+                        if self.report_format == ReportFormat::Text {
+                            let skipped = Style::Yellow.paint("Skipped:");
+                            println!("{skipped} {} is lossy {codec} audio", input_file.display());
+                        }
+                        return Ok(Outcome::Skipped);
+                    }
+                }
+            }
+        }
+
+        // --album-folder, --alphabetize, and slashes in --name-template can all add directories
+        self.create_dir(output_file.parent().expect("output file has a directory"))?;
+        let temp_file = self.temp_file(&output_file);
+
+        let cover = self.cover_art(track, &input_file)?;
+        let mut cmd = self.ffmpeg_command(track, artist, &input_file, cover);
+        let duration = if self.progress {
+            cmd.args(
+                "write progress updates to stdout instead of the usual stats line",
+                ["-progress", "pipe:1", "-nostats"],
+            );
+            Some(self.probe_duration(track)?.0)
+        } else {
+            None
+        };
+        cmd.args(
+            "the temporary output file, which is renamed when it's complete",
+            [&temp_file],
+        );
+        // only explain the first track's command, unless --verbose
+        static EXPLAINED: AtomicBool = AtomicBool::new(false);
+        if self.explain && (self.verbose || !EXPLAINED.swap(true, Ordering::Relaxed)) {
+            print!("{}", cmd.explain(&track.file));
+        }
+        let mut cmd = cmd.cmd;
+
+        if self.verbose {
+            println!("+ {cmd:?}");
+        }
+
+        let stream_prefix = self.verbose.then(|| track.file.display().to_string());
+        // the last 10% step that was printed for this track
+        let last_step = AtomicU32::new(0);
+        let on_progress = |line: &str| {
+            let Some(duration) = duration.filter(|&d| d > 0.0) else {
+                return;
+            };
+            let out_time = line
+                .strip_prefix("out_time_us=")
+                .or_else(|| line.strip_prefix("out_time_ms="))
+                .and_then(|us| us.parse::<f64>().ok());
+            if let Some(out_time) = out_time {
+                // out_time_ms is actually in microseconds too
+                let percent = (out_time / 1e6 / duration * 100.0).clamp(0.0, 100.0) as u32;
+                let step = percent / 10 * 10;
+                if step > last_step.fetch_max(step, Ordering::Relaxed) {
+                    println!("[{}] {step}%", track.file.display());
+                }
+            }
+        };
+        let output = self
+            .runner
+            .run(
+                &mut cmd,
+                stream_prefix.as_deref(),
+                duration.is_some().then_some(&on_progress as _),
+            )
+            .with_context(|| format!("failed to execute {cmd:?}"))?;
+        if output.status.success() {
+            if self.show_ffmpeg_output {
+                // with --progress, stdout is only ffmpeg's progress updates
+                let mut text = if self.progress {
+                    String::new()
+                } else {
+                    String::from_utf8_lossy(&output.stdout).into_owned()
+                };
+                // with --verbose, stderr was already printed while ffmpeg ran
+                if !self.verbose {
+                    text.push_str(&String::from_utf8_lossy(&output.stderr));
+                }
+                for line in text.lines() {
+                    println!("[{}] {line}", track.file.display());
+                }
+            }
+            let warnings = self.ffmpeg_warnings(&output.stderr);
+            if !warnings.is_empty() {
+                let _ = std::fs::remove_file(&temp_file);
+                bail!(
+                    "ffmpeg logged warnings while converting {}, failing because of \
+                     --halt-on-warning:\n{}",
+                    track.file.display(),
+                    warnings.join("\n")
+                );
+            }
+            let post_process = || -> Result<()> {
+                if self.needs_metaflac() && self.format == Format::Flac {
+                    self.run_metaflac(&temp_file, artist)?;
+                }
+                if self.flac_verify && self.format == Format::Flac {
+                    self.verify_flac(&temp_file)?;
+                }
+                if let Some(mode) = self.chmod {
+                    set_mode(&temp_file, mode)?;
+                }
+                Ok(())
+            };
+            if let Err(err) = post_process() {
+                let _ = std::fs::remove_file(&temp_file);
+                return Err(err);
+            }
+            self.move_into_place(&temp_file, &output_file)?;
+            self.append_manifest(&output_file)?;
+            self.append_checksum(&output_file)?;
+            if self.report_format == ReportFormat::Text {
+                if self.show_tags {
+                    let mut tags = match artist {
+                        Some(artist) => format!("{artist} - {}", track.title),
+                        None => track.title.clone(),
+                    };
+                    if let Some(disc) = track.disc {
+                        tags += &format!(", disc {disc}");
+                    }
+                    if let Some(num) = track.track {
+                        tags += &format!(", track {num}");
+                    }
+                    let ok = Style::Green.paint("OK:");
+                    println!("{ok} {} [{tags}]", output_file.display());
+                } else {
+                    println!("{} {}", Style::Green.paint("OK:"), output_file.display());
+                }
+            }
+            Ok(Outcome::Converted)
+        } else {
+            // ffmpeg may have written a partial file, don't leave it lying around
+            let _ = std::fs::remove_file(&temp_file);
+            let err = anyhow!(
+                "failed to convert {infile} into {outfile}: ffmpeg command failed\n\
+                 \n\
+                 command: {cmd:?}\n\
+                 \n\
+                 standard output:\n\
+                 {stdout}\n\
+                 \n\
+                 standard error:\n\
+                 {stderr}\n",
+                infile = track.file.display(),
+                outfile = output_file.display(),
+                cmd = cmd,
+                stdout = String::from_utf8_lossy(&output.stdout),
+                stderr = String::from_utf8_lossy(&output.stderr),
+            );
+            match self.fallback() {
+                Some(fallback) if self.encoder_missing(&output.stderr) => {
+                    let ext = fallback.format.extension();
+                    if let Err(warning) = self.warn(format_args!(
+                        "failed to convert {} to {}, retrying in {ext}",
+                        track.file.display(),
+                        self.format.extension(),
+                    )) {
+                        return Err(err.context(warning));
+                    }
+                    fallback.convert_track(track).map_err(|fallback_err| {
+                        fallback_err
+                            .context(format!("retried in {ext}, which also failed"))
+                            .context(err)
+                    })
+                }
+                _ => Err(err),
+            }
+        }
+    }
+
+    /// Whether ffmpeg failed because it doesn't have the encoder for the output format, either
+    /// because check_encoder found it missing or because ffmpeg said so
+    fn encoder_missing(&self, stderr: &[u8]) -> bool {
+        let stderr = String::from_utf8_lossy(stderr);
+        self.missing_encoders.contains(&self.format)
+            || stderr.contains("Unknown encoder")
+            || stderr.contains("Encoder not found")
+    }
+
+    /// Find the lines of ffmpeg's stderr that are warnings or errors for --halt-on-warning, except
+    /// for ones matching --ignore-warning. ffmpeg tags each message with its level, like
+    /// "[aist#0:0/pcm_s16le @ 0x5581] [warning] Guessed Channel Layout: stereo".
+    fn ffmpeg_warnings(&self, stderr: &[u8]) -> Vec<String> {
+        if !self.halt_on_warning {
+            return Vec::new();
+        }
+        let ignore = self
+            .ignore_warning
+            .iter()
+            .map(|pattern| pattern.to_lowercase())
+            .collect::<Vec<_>>();
+        String::from_utf8_lossy(stderr)
+            .lines()
+            .filter(|line| {
+                ["[warning]", "[error]", "[fatal]", "[panic]"]
+                    .iter()
+                    .any(|level| line.contains(level))
+            })
+            .filter(|line| {
+                let line = line.to_lowercase();
+                !ignore.iter().any(|pattern| line.contains(pattern))
+            })
+            .map(str::to_owned)
+            .collect()
+    }
+
+    /// Convert audio from stdin and write it to stdout, without using a CSV or output directory.
+    /// Anything we print goes to stderr so that it doesn't get mixed in with the audio.
+    fn convert_pipe(&self) -> Result<()> {
+        let track = Track {
+            file: PathBuf::from("pipe:0"),
+            title: self.title.clone().unwrap_or_default(),
+            artist: self.artist.clone(),
+            ..Default::default()
+        };
+        let artist = self.track_artist(&track)?;
+
+        let cover = self.cover.as_deref().map_or(CoverArt::None, CoverArt::File);
+        let mut cmd = self.ffmpeg_command(&track, artist, &track.file, cover).cmd;
+        cmd.args(["-f", self.format.extension(), "pipe:1"]);
+        if self.verbose {
+            eprintln!("+ {cmd:?}");
+        }
+
+        let status = cmd
+            .stdin(Stdio::inherit())
+            .stdout(Stdio::inherit())
+            .status()
+            .with_context(|| format!("failed to execute {cmd:?}"))?;
+        if !status.success() {
+            bail!("ffmpeg command failed: {status}");
+        }
+        Ok(())
+    }
+
+    /// Read the list of tracks, either from the CSV file or by scanning a directory when using
+    /// --parse-filename
+    fn read_tracks(&mut self) -> Result<Vec<Track>> {
+        let input = self.input_csv.as_deref().expect("input_csv is required");
+
+        if let Some(pattern) = &self.parse_filename {
+            let mut files = list_dir(input, AUDIO_EXTENSIONS)?;
+            if self.disc_from_subdir {
+                for entry in std::fs::read_dir(input).context("failed to read input directory")? {
+                    let path = entry.context("failed to read input directory")?.path();
+                    let is_disc_dir = path
+                        .file_name()
+                        .and_then(|name| name.to_str())
+                        .is_some_and(|name| disc_from_dir_name(name).is_some());
+                    if is_disc_dir && path.is_dir() {
+                        files.extend(list_dir(&path, AUDIO_EXTENSIONS)?);
+                    }
+                }
+                files.sort();
+            }
+            return files.into_iter().map(|file| pattern.track(file)).collect();
+        }
+
+        if self.file_list {
+            let list = std::fs::read_to_string(input).context("failed to open file list")?;
+            return list
+                .lines()
+                .map(str::trim)
+                .enumerate()
+                .filter(|(_, line)| !line.is_empty())
+                .enumerate()
+                .map(|(i, (row, line))| {
+                    let file = PathBuf::from(line);
+                    let title = file
+                        .file_stem()
+                        .and_then(|s| s.to_str())
+                        .ok_or_else(|| anyhow!("invalid filename {line}"))?
+                        .to_owned();
+                    Ok(Track {
+                        file,
+                        track: Some(i as u32 + 1),
+                        title,
+                        row: Some(row + 1),
+                        ..Default::default()
+                    })
+                })
+                .collect();
+        }
+
+        let csv_bytes = std::fs::read(input).context("failed to open input file")?;
+        let csv_text = String::from_utf8(csv_bytes).map_err(|err| {
+            let valid = &err.as_bytes()[..err.utf8_error().valid_up_to()];
+            let line = valid.iter().filter(|&&b| b == b'\n').count() + 1;
+            anyhow!("invalid UTF-8 on line {line} of the CSV file, it may be corrupt")
+        })?;
+        let body = self.apply_csv_comments(&csv_text)?;
+        // Replace the comment lines with blank lines, which the csv crate skips, so that line
+        // numbers in its errors and in track.row match the whole file
+        let comment_lines = csv_text[..csv_text.len() - body.len()]
+            .matches('\n')
+            .count();
+        let body = "\n".repeat(comment_lines) + body;
+
+        let mut reader = csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .from_reader(body.as_bytes());
+        let headers = reader
+            .headers()
+            .context("failed to parse CSV file")?
+            .clone();
+
+        // Spreadsheet exports often end with rows of empty cells like ",,,", skip those rather
+        // than failing on them. Rows that only have some empty cells are still parsed normally.
+        //
+        // Neat, you can collect from an iterator of Results into a Result of a collection. Returns
+        // Ok(collection) if every value was Ok, or Err(e) of the first Err item.
+        reader
+            .records()
+            .filter(|record| !record.as_ref().is_ok_and(|r| r.iter().all(str::is_empty)))
+            .map(|record| {
+                let record = record?;
+                let mut track = record.deserialize::<Track>(Some(&headers))?;
+                track.row = record.position().map(|pos| pos.line() as usize);
+                Ok(track)
+            })
+            .collect::<Result<Vec<Track>, csv::Error>>()
+            .context("failed to parse CSV file")
+    }
+
+    /// Look for a cover art file in the input directory for --auto-cover
+    fn find_cover(&self) -> Result<Option<PathBuf>> {
+        let dir = match (&self.input_dir, &self.parse_filename) {
+            (Some(dir), _) => dir.as_path(),
+            (None, Some(_)) => self.input_csv.as_deref().expect("input_csv is required"),
+            (None, None) => Path::new("."),
+        };
+        let names = if self.cover_name.is_empty() {
+            COVER_NAMES.to_vec()
+        } else {
+            self.cover_name.iter().map(String::as_str).collect()
+        };
+        let extensions = names
+            .iter()
+            .filter_map(|name| name.rsplit_once('.').map(|(_, ext)| ext))
+            .collect::<Vec<_>>();
+        let files = list_dir(dir, &extensions)?;
+        Ok(names
+            .iter()
+            .find_map(|name| {
+                files.iter().find(|file| {
+                    file.file_name()
+                        .is_some_and(|file| file.eq_ignore_ascii_case(name))
+                })
+            })
+            .cloned())
+    }
+
+    /// Parse the date from a leading year in the input directory's name for --infer-date, e.g.
+    /// "2019 - Album Name"
+    fn infer_date(&self) -> Result<Option<String>> {
+        let dir = match (&self.input_dir, &self.parse_filename) {
+            (Some(dir), _) => dir,
+            (None, Some(_)) => self.input_csv.as_ref().expect("input_csv is required"),
+            (None, None) => {
+                self.warn("--infer-date has no effect without --input-dir")?;
+                return Ok(None);
+            }
+        };
+        // canonicalize so that paths like "." still have a name
+        let name = dir
+            .canonicalize()
+            .ok()
+            .and_then(|dir| {
+                dir.file_name()
+                    .map(|name| name.to_string_lossy().into_owned())
+            })
+            .unwrap_or_default();
+        let digits = name.chars().take_while(char::is_ascii_digit).count();
+        if digits == 4 {
+            Ok(Some(name[..4].to_owned()))
+        } else {
+            self.warn(format_args!(
+                "--infer-date couldn't find a year at the start of '{name}'"
+            ))?;
+            Ok(None)
+        }
+    }
+
+    /// Prepare the album, or every album with --albums, in one output format. With --albums, an
+    /// album that fails to prepare is kept as an error so that the others can still be converted.
+    fn prepare_batch(mut self) -> Result<Batch> {
+        if self.albums {
+            return self.prepare_albums();
+        }
+        let tracks = self.prepare_album()?;
+        Ok(vec![Ok((self, tracks))])
+    }
+
+    /// Convert the albums from prepare_batch
+    fn convert_batch(&self, batch: Batch) -> Result<Summary> {
+        if self.albums {
+            return self.convert_albums(batch);
+        }
+        let mut summary = Summary::default();
+        for res in batch {
+            let (mut album, tracks) = res?;
+            if let Some(path) = &album.export_album {
+                album.export_album(path, &tracks)?;
+            }
+            summary.merge(album.convert_tracks(tracks)?);
+        }
+        Ok(summary)
+    }
+
+    /// Read an album's tracks and check them before converting anything. This fills in computed
+    /// settings like --auto-totals, and applies --only, --skip, and --single-file.
+    fn prepare_album(&mut self) -> Result<Vec<Track>> {
+        if self.input_dir_from_csv && self.input_dir.is_none() {
+            let csv_file = self.input_csv.as_deref().expect("input_csv is required");
+            self.input_dir = csv_file
+                .parent()
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .map(Path::to_owned);
+        }
+        let mut tracks = self.read_tracks()?;
+        for (i, track) in tracks.iter_mut().enumerate() {
+            track.number = i + 1;
+        }
+        self.listed_tracks = tracks.len();
+        if tracks.is_empty() {
+            let input = self.input_csv.as_deref().expect("input_csv is required");
+            self.warn(format_args!("no tracks found in {}", input.display()))?;
+        }
+        if let Some(dir) = &self.sidecar_dir {
+            for track in &mut tracks {
+                self.apply_track_sidecar(dir, track)?;
+            }
+        }
+        for track in &mut tracks {
+            let Some(position) = track.position else {
+                continue;
+            };
+            let conflict = |column: Option<u32>, value: Option<u32>| {
+                column
+                    .zip(value)
+                    .is_some_and(|(column, value)| column != value)
+            };
+            if conflict(track.disc, position.disc) || conflict(track.track, Some(position.track)) {
+                bail!(
+                    "{}: position {position} doesn't match the disc and track columns",
+                    self.track_location(track)
+                );
+            }
+            track.disc = track.disc.or(position.disc);
+            track.track = track.track.or(Some(position.track));
+        }
+        for track in &mut tracks {
+            if let Some(cover) = &track.cover {
+                track.cover = Some(self.input_path(cover).into_owned());
+            }
+        }
+        if self.disc_from_subdir {
+            for track in tracks.iter_mut().filter(|track| track.disc.is_none()) {
+                track.disc = track
+                    .file
+                    .parent()
+                    .and_then(Path::file_name)
+                    .and_then(|name| name.to_str())
+                    .and_then(disc_from_dir_name);
+            }
+        }
+        if self.unescape_titles {
+            for track in &mut tracks {
+                track.title = unescape_underscores(&track.title);
+                if let Some(artist) = &mut track.artist {
+                    *artist = unescape_underscores(artist);
+                }
+            }
+        }
+        if self.infer_date && self.date.is_none() {
+            self.date = self.infer_date()?;
+        }
+        if self.auto_cover && self.cover.is_none() {
+            self.cover = self.find_cover()?;
+            if let (Some(cover), true) = (&self.cover, self.verbose) {
+                println!("Using cover art {}", cover.display());
+            }
+        }
+        if self.album_folder && self.album_title.is_none() {
+            self.warn("--album-folder was given but the album has no title, writing files to the output directory")?;
+        }
+        if self.cover.is_some() && !self.format.supports_cover() {
+            self.warn(format_args!(
+                "{} output can't hold cover art, so the cover won't be embedded",
+                self.format.extension()
+            ))?;
+        }
+
+        let mut formats = self.formats();
+        formats.extend(self.fallback_format);
+        for track in &tracks {
+            if track.file.as_os_str().is_empty() {
+                bail!("{}: the file column is empty", self.track_location(track));
+            }
+            self.track_artist(track)
+                .with_context(|| self.track_location(track))?;
+            // Control characters in a filename almost certainly mean that the CSV is corrupt
+            let file = track.file.to_string_lossy();
+            if let Some(c) = file.chars().find(|c| c.is_control()) {
+                bail!(
+                    "{}: file path {file:?} contains the control character {c:?}, the CSV may be \
+                     corrupt",
+                    self.track_location(track)
+                );
+            }
+            let row_settings = track
+                .compression
+                .map(|level| check_compression(&formats, level))
+                .into_iter()
+                .chain(track.quality.map(|q| check_quality(&formats, q)));
+            for res in row_settings {
+                res.with_context(|| self.track_location(track))?;
+            }
+            if self.rename_only && (track.compression.is_some() || track.quality.is_some()) {
+                bail!(
+                    "{}: --rename-only can't change compression or quality",
+                    self.track_location(track)
+                );
+            }
+            if let (Some(start), Some(end)) = (track.start, track.end) {
+                if end <= start {
+                    bail!(
+                        "{}: end time {end} is not after start time {start}",
+                        self.track_location(track)
+                    );
+                }
+            }
+        }
+
+        for (opt, indices) in [("--only", &self.only), ("--skip", &self.skip)] {
+            if let Some(max) = indices.as_ref().map(TrackIndices::max) {
+                if max > tracks.len() {
+                    bail!(
+                        "{opt} track {max} is out of range, the CSV has {} tracks",
+                        tracks.len()
+                    );
+                }
+            }
+        }
+        let mut discs = HashSet::new();
+        for track in &mut tracks {
+            track.first_on_disc = discs.insert(track.disc);
+        }
+        if self.auto_totals {
+            // count before filtering with --only/--skip, the totals are for the whole album
+            for track in &tracks {
+                *self.track_totals.entry(track.disc).or_default() += 1;
+            }
+            let discs = tracks.iter().filter_map(|t| t.disc).collect::<HashSet<_>>();
+            self.disc_total = (!discs.is_empty()).then_some(discs.len() as u32);
+        }
+        if self.tag_album_duration && !self.list_tracks && !self.dry_run {
+            let durations = tracks
+                .par_iter()
+                .map(|track| self.probe_duration(track).map(|(duration, _)| duration))
+                .collect::<Result<Vec<_>>>()?;
+            self.album_duration = Some(durations.iter().sum());
+        }
+
+        // before --only and --skip, so that names don't depend on which tracks are converted
+        if self.hash_on_collision {
+            self.hash_colliding_names(&mut tracks)?;
+        } else if !self.single_file && !self.pipe {
+            self.check_colliding_names(&tracks)?;
+        }
+
+        let tracks = tracks
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| self.only.as_ref().is_none_or(|only| only.contains(i + 1)))
+            .filter(|(i, _)| !self.skip.as_ref().is_some_and(|skip| skip.contains(i + 1)))
+            .map(|(_, track)| track)
+            .collect::<Vec<_>>();
+        if tracks.is_empty() && self.listed_tracks > 0 {
+            self.warn("--only and --skip left no tracks to convert")?;
+        }
+
+        if self.single_file {
+            Ok(vec![self.single_file_track(&tracks)?])
+        } else {
+            Ok(tracks)
+        }
+    }
+
+    /// Tell apart tracks with the same output filename by adding a hash of their input files, for
+    /// --hash-on-collision
+    fn hash_colliding_names(&self, tracks: &mut [Track]) -> Result<()> {
+        let mut outputs = HashMap::<PathBuf, Vec<usize>>::new();
+        for (i, track) in tracks.iter().enumerate() {
+            outputs.entry(self.output_file(track)?).or_default().push(i);
+        }
+        let mut hashes = HashMap::<PathBuf, String>::new();
+        for i in outputs.into_values().filter(|v| v.len() > 1).flatten() {
+            let input_file = self.input_file(&tracks[i]).into_owned();
+            let hash = match hashes.get(&input_file) {
+                Some(hash) => hash.clone(),
+                None => {
+                    let mut hash = sha256_file(&input_file)
+                        .with_context(|| self.track_location(&tracks[i]))?;
+                    hash.truncate(8);
+                    hashes.insert(input_file, hash.clone());
+                    hash
+                }
+            };
+            tracks[i].name_hash = Some(hash);
+        }
+
+        let mut seen = HashSet::new();
+        for track in tracks.iter() {
+            let output_file = self.output_file(track)?;
+            if !seen.insert(output_file.clone()) {
+                bail!(
+                    "{}: output file {} is used by another track with the same input file",
+                    self.track_location(track),
+                    output_file.display()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Fail if two tracks have the same output filename, since the later one would overwrite the
+    /// earlier one. This is easy to hit with --flat or --name-template, which can leave out the
+    /// disc and track numbers.
+    fn check_colliding_names(&self, tracks: &[Track]) -> Result<()> {
+        let mut outputs = HashMap::<PathBuf, &Track>::new();
+        for track in tracks {
+            let output_file = self.output_file(track)?;
+            if let Some(other) = outputs.insert(output_file.clone(), track) {
+                bail!(
+                    "{} and {} would both be written to {}, use --hash-on-collision to tell them \
+                     apart",
+                    self.track_location(other),
+                    self.track_location(track),
+                    output_file.display()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Ask before converting more than CONFIRM_TRACKS tracks or overwriting existing files, unless
+    /// --assume-yes. There's no prompt if stdin isn't a terminal or the report is JSON, or when
+    /// nothing will be converted.
+    fn confirm_batch(&self, albums: &[(&Args, &Vec<Track>)]) -> Result<()> {
+        /// Batches with more tracks than this need confirmation
+        const CONFIRM_TRACKS: usize = 100;
+
+        if self.assume_yes
+            || self.list_tracks
+            || self.dry_run
+            || self.compare_existing
+            || self.report_format == ReportFormat::Json
+            || !io::stdin().is_terminal()
+        {
+            return Ok(());
+        }
+        let count = albums.iter().map(|(_, tracks)| tracks.len()).sum::<usize>();
+        let existing = albums
+            .iter()
+            .flat_map(|(album, tracks)| tracks.iter().map(move |track| album.output_file(track)))
+            .filter_map(Result::ok)
+            .filter(|file| file.exists() && !self.already_finished(file))
+            .count();
+        if count <= CONFIRM_TRACKS && existing == 0 {
+            return Ok(());
+        }
+
+        let mut prompt = format!("Convert {count} tracks to {}", self.output_dir().display());
+        if existing > 0 {
+            prompt += &format!(", overwriting {existing} existing files");
+        }
+        print!("{prompt}? [y/N] ");
+        io::stdout().flush().context("failed to write prompt")?;
+        let mut answer = String::new();
+        io::stdin()
+            .read_line(&mut answer)
+            .context("failed to read answer")?;
+        match answer.trim().to_lowercase().as_str() {
+            "y" | "yes" => Ok(()),
+            _ => bail!("cancelled"),
+        }
+    }
+
+    /// Convert an album's tracks, after prepare_album has checked them
+    fn convert_tracks(&mut self, tracks: Vec<Track>) -> Result<Summary> {
+        if self.list_tracks {
+            return self.list_tracks(&tracks).map(|()| Summary::default());
+        }
+        if self.dry_run {
+            return self.estimate_sizes(&tracks).map(|()| Summary::default());
+        }
+        if self.compare_existing {
+            return self.compare_existing(&tracks).map(|()| Summary::default());
+        }
+        if tracks.is_empty() {
+            return Ok(Summary {
+                listed: self.listed_tracks,
+                ..Default::default()
+            });
+        }
+
+        self.create_dir(self.output_dir())?;
+        if let Some(dir) = &self.tempdir {
+            std::fs::create_dir_all(dir).context("failed to create temporary directory")?;
+        }
+
+        if self.album_normalize && !tracks.is_empty() {
+            let loudness = tracks
+                .par_iter()
+                .map(|track| self.measure_loudness(track))
+                .collect::<Result<Vec<_>>>()?;
+            let gain = album_gain(&loudness);
+            if self.report_format == ReportFormat::Text {
+                println!("Album gain: {gain:+.2} dB");
+            }
+            self.album_gain = Some(gain);
+        }
+
+        // Unless --keep-going, once any track fails don't start converting any more of them. Tracks
+        // that are already in progress will still finish. Running low on space would fail every
+        // later track too, so that stops even with --keep-going. Collecting preserves CSV order,
+        // so the error we return is the first failure in the CSV.
+        let abort = AtomicBool::new(false);
+        let results = tracks
+            .par_iter()
+            .map(|track| {
+                let _permit = self.inflight.as_ref().map(|inflight| inflight.acquire());
+                if abort.load(Ordering::Relaxed) || INTERRUPTED.load(Ordering::Relaxed) {
+                    return Ok(Outcome::Skipped);
+                }
+                let free_space = self.check_free_space();
+                if free_space.is_err() {
+                    abort.store(true, Ordering::Relaxed);
+                }
+                let res = free_space
+                    .and_then(|()| self.convert_track(track))
+                    .with_context(|| self.track_location(track));
+                match &res {
+                    Ok(outcome) => outcome.tally(),
+                    Err(_) => {
+                        Outcome::Failed.tally();
+                        if !self.keep_going {
+                            abort.store(true, Ordering::Relaxed);
+                        }
+                    }
+                }
+                res
+            })
+            .collect::<Vec<_>>();
+
+        let mut summary = Summary {
+            listed: self.listed_tracks,
+            ..Default::default()
+        };
+        for (track, res) in tracks.iter().zip(results) {
+            summary.record(track.file.clone(), self.converted_output(track).ok(), res);
+        }
+        if INTERRUPTED.load(Ordering::Relaxed) && summary.error.is_none() {
+            summary.error = Some(anyhow!("interrupted"));
+        }
+
+        if let (Some(format), None) = (self.sidecar, &summary.error) {
+            self.write_sidecar(format, &tracks)?;
+        }
+        Ok(summary)
+    }
+
+    /// Rename existing audio files to match the output naming scheme, for --normalize-filenames
+    fn normalize_filenames(&self, dir: &Path) -> Result<()> {
+        let extensions = Format::ALL.map(Format::extension);
+        let mut renamed = 0;
+        for file in list_dir(dir, &extensions)? {
+            let (_, tags) = self.probe_tags(&file)?;
+            let number = |key: &str| {
+                tags.get(key)
+                    .and_then(|n| n.split('/').next())
+                    .and_then(|n| n.trim().parse().ok())
+            };
+            let Some(title) = tags.get("title") else {
+                self.warn(format_args!(
+                    "{} has no title tag, skipping it",
+                    file.display()
+                ))?;
+                continue;
+            };
+            let track = Track {
+                file: file.clone(),
+                disc: number("disc"),
+                track: number("track"),
+                title: title.clone(),
+                artist: tags.get("artist").cloned(),
+                ..Default::default()
+            };
+
+            // Files keep their format, so ignore any extension from --name-template
+            let mut args = self.clone();
+            args.output_dir = Some(dir.to_owned());
+            args.template_extension = None;
+            args.album_artist = tags.get("album_artist").cloned().or(args.album_artist);
+            if let Some(format) = file
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .and_then(Format::from_extension)
+            {
+                args.format = format;
+            }
+            let new_file = args
+                .output_file(&track)
+                .with_context(|| file.display().to_string())?;
+            if new_file == file {
+                continue;
+            }
+            if new_file.exists() {
+                self.warn(format_args!(
+                    "not renaming {} because {} already exists",
+                    file.display(),
+                    new_file.display()
+                ))?;
+                continue;
+            }
+            if self.dry_run {
+                println!("Would rename {} -> {}", file.display(), new_file.display());
+            } else {
+                self.create_dir(new_file.parent().expect("output file has a directory"))?;
+                std::fs::rename(&file, &new_file).with_context(|| {
+                    format!(
+                        "failed to rename {} to {}",
+                        file.display(),
+                        new_file.display()
+                    )
+                })?;
+                println!("Renamed {} -> {}", file.display(), new_file.display());
+            }
+            renamed += 1;
+        }
+        let verb = if self.dry_run {
+            "would be renamed"
+        } else {
+            "renamed"
+        };
+        println!("{renamed} files {verb}");
+        Ok(())
+    }
+
+    /// For --single-file, replace the album's tracks with one track covering the whole input file,
+    /// and build a cue sheet listing where each of the original tracks starts
+    fn single_file_track(&mut self, tracks: &[Track]) -> Result<Track> {
+        let Some(first) = tracks.first() else {
+            bail!("--single-file needs at least one track");
+        };
+        if let Some(other) = tracks.iter().find(|track| track.file != first.file) {
+            bail!(
+                "--single-file needs every track to come from the same input file, but the CSV \
+                 has both {} and {}",
+                first.file.display(),
+                other.file.display()
+            );
+        }
+        let album = Track {
+            file: first.file.clone(),
+            title: self.album_title.clone().unwrap_or_else(|| {
+                first
+                    .file
+                    .file_stem()
+                    .unwrap_or_default()
+                    .to_string_lossy()
+                    .into_owned()
+            }),
+            artist: self.album_artist.clone().or_else(|| first.artist.clone()),
+            genre: first.genre.clone(),
+            first_on_disc: true,
+            ..Default::default()
+        };
+
+        // Cue sheets don't have any way to escape quotes
+        let quote = |s: &str| format!("\"{}\"", s.replace('"', "'"));
+        let output = self.output_file(&album)?;
+        let mut cue = String::new();
+        if let Some(artist) = &album.artist {
+            cue += &format!("PERFORMER {}\n", quote(artist));
+        }
+        cue += &format!("TITLE {}\n", quote(&album.title));
+        let name = output.file_name().unwrap_or_default().to_string_lossy();
+        cue += &format!("FILE {} WAVE\n", quote(&name));
+        let mut sorted = tracks.iter().collect::<Vec<_>>();
+        sorted.sort_by(|a, b| {
+            a.start
+                .partial_cmp(&b.start)
+                .expect("timestamps are finite")
+        });
+        for (i, track) in sorted.iter().enumerate() {
+            // cue sheet times are minutes, seconds, and CD frames (1/75 second)
+            let frames = (track.start.map_or(0.0, |t| t.0) * 75.0).round() as u64;
+            let (minutes, seconds, frames) = (frames / 75 / 60, frames / 75 % 60, frames % 75);
+            cue += &format!("  TRACK {:02} AUDIO\n", track.track.unwrap_or(i as u32 + 1));
+            cue += &format!("    TITLE {}\n", quote(&track.title));
+            if let Some(artist) = self.track_artist(track)? {
+                cue += &format!("    PERFORMER {}\n", quote(artist));
+            }
+            cue += &format!("    INDEX 01 {minutes:02}:{seconds:02}:{frames:02}\n");
+        }
+        self.cuesheet = Some(cue);
+        Ok(album)
+    }
+
+    /// Probe a track's input file with ffprobe, and return the track's duration in seconds, which
+    /// takes its start and end times into account, along with the rest of ffprobe's output
+    fn probe_duration(&self, track: &Track) -> Result<(f64, serde_json::Value)> {
+        let input_file = self.input_file(track);
+        let mut cmd = self.ffprobe();
+        cmd.args(["-v", "error", "-select_streams", "a:0", "-of", "json"]);
+        cmd.args([
+            "-show_entries",
+            "format=duration,bit_rate:\
+             stream=sample_rate,channels,bits_per_raw_sample,bits_per_sample",
+        ]);
+        cmd.arg(&*input_file);
+        if self.verbose {
+            println!("+ {cmd:?}");
+        }
+        let output = self
+            .runner
+            .output(&mut cmd)
+            .with_context(|| format!("failed to execute {cmd:?}"))?;
+        if !output.status.success() {
+            bail!(
+                "failed to probe {}: {}",
+                input_file.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        let probe: serde_json::Value =
+            serde_json::from_slice(&output.stdout).with_context(|| {
+                format!(
+                    "failed to parse ffprobe output for {}",
+                    input_file.display()
+                )
+            })?;
+        let duration = probe_number(&probe["format"]["duration"]).with_context(|| {
+            format!(
+                "ffprobe didn't report a duration for {}",
+                input_file.display()
+            )
+        })?;
+        let end = track.end.map_or(duration, |end| end.0.min(duration));
+        let duration = (end - track.start.map_or(0.0, |start| start.0)).max(0.0);
+        Ok((duration, probe))
+    }
+
+    /// Estimate the size of a track's output file in bytes for --dry-run, and return it along with
+    /// the track's duration in seconds
+    fn estimate_size(&self, track: &Track) -> Result<(f64, u64)> {
+        let (duration, probe) = self.probe_duration(track)?;
+        let stream = &probe["streams"][0];
+
+        let bits_per_second = if self.rename_only {
+            probe_number(&probe["format"]["bit_rate"]).unwrap_or(0.0)
+        } else {
+            match self.format {
+                Format::Flac | Format::WavPack => {
+                    let sample_rate = probe_number(&stream["sample_rate"]).unwrap_or(44100.0);
+                    let channels = probe_number(&stream["channels"]).unwrap_or(2.0);
+                    let bits = self.bit_depth.map(f64::from).unwrap_or_else(|| {
+                        probe_number(&stream["bits_per_raw_sample"])
+                            .or_else(|| probe_number(&stream["bits_per_sample"]))
+                            .unwrap_or(16.0)
+                    });
+                    // FLAC and WavPack typically compress music to around 60% of the PCM size
+                    sample_rate * channels * bits * 0.6
+                }
+                Format::Mp3 => match (track.quality.or(self.quality), &self.bitrate) {
+                    // Approximate average bitrates of LAME's VBR presets V0 to V9
+                    (Some(quality), _) => {
+                        const VBR: [f64; 10] = [
+                            245.0, 225.0, 190.0, 175.0, 165.0, 130.0, 115.0, 100.0, 85.0, 65.0,
+                        ];
+                        VBR[quality.min(9) as usize] * 1000.0
+                    }
+                    (None, Some(bitrate)) => bitrate_bps(bitrate),
+                    // ffmpeg's default for libmp3lame
+                    (None, None) => 128_000.0,
+                },
+                Format::Opus => match self.bitrate.as_ref().or(self.max_bitrate.as_ref()) {
+                    Some(bitrate) => bitrate_bps(bitrate),
+                    // libopus picks about 48 kbps per channel by default
+                    None => 48_000.0 * probe_number(&stream["channels"]).unwrap_or(2.0).min(2.0),
+                },
+            }
+        };
+        Ok((duration, (duration * bits_per_second / 8.0) as u64))
+    }
+
+    /// Print the estimated size of each output file and the total, for --dry-run
+    fn estimate_sizes(&self, tracks: &[Track]) -> Result<()> {
+        let estimates = tracks
+            .par_iter()
+            .map(|track| self.estimate_size(track))
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut rows = vec![[
+            "OUTPUT".to_owned(),
+            "DURATION".to_owned(),
+            "ESTIMATE".to_owned(),
+        ]];
+        for (track, (duration, size)) in tracks.iter().zip(&estimates) {
+            rows.push([
+                self.output_file(track)?.display().to_string(),
+                format_duration(*duration),
+                format_size(*size),
+            ]);
+        }
+        let total = estimates.iter().map(|(_, size)| size).sum::<u64>();
+
+        // Like --list-tracks, build the whole table first so that --albums output doesn't
+        // interleave
+        let mut table = String::new();
+        if self.albums {
+            let csv_file = self.input_csv.as_deref().expect("input_csv is required");
+            table += &format!("{}:\n", csv_file.display());
+        }
+        table += &format_table(&rows);
+        table += &format!("Estimated total: {}", format_size(total));
+        if let Ok(free) = free_space(self.output_dir()) {
+            table += &format!(" ({} free)", format_size(free));
+        }
+        table.push('\n');
+        print!("{table}");
+        Ok(())
+    }
+
+    /// Print a table of how each track will be converted for --list-tracks
+    fn list_tracks(&self, tracks: &[Track]) -> Result<()> {
+        let mut rows = vec![[
+            "INPUT".to_owned(),
+            "DISC".to_owned(),
+            "TRACK".to_owned(),
+            "ARTIST".to_owned(),
+            "TITLE".to_owned(),
+            "OUTPUT".to_owned(),
+        ]];
+        for track in tracks {
+            let number = |n: Option<u32>| n.map(|n| n.to_string()).unwrap_or_default();
+            let artist = match self.track_artist(track)? {
+                Some(artist) if track.artist.as_deref() == Some(artist) => artist.to_owned(),
+                Some(artist) => format!("{artist} (album artist)"),
+                None => String::new(),
+            };
+            rows.push([
+                self.input_file(track).display().to_string(),
+                number(track.disc),
+                number(track.track),
+                artist,
+                track.title.clone(),
+                self.output_file(track)?.display().to_string(),
+            ]);
+        }
+
+        // Build the whole table before printing it, so that tables from --albums don't interleave
+        let mut table = String::new();
+        if self.albums {
+            let csv_file = self.input_csv.as_deref().expect("input_csv is required");
+            table += &format!("{}:\n", csv_file.display());
+        }
+        table += &format_table(&rows);
+        print!("{table}");
+        Ok(())
+    }
+
+    /// Print what converting each track would do to its existing output file, for
+    /// --compare-existing
+    fn compare_existing(&self, tracks: &[Track]) -> Result<()> {
+        let dispositions = tracks
+            .par_iter()
+            .map(|track| self.disposition(track))
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut rows = vec![["OUTPUT".to_owned(), "STATUS".to_owned()]];
+        let mut counts = BTreeMap::new();
+        for (track, disposition) in tracks.iter().zip(&dispositions) {
+            rows.push([
+                self.output_file(track)?.display().to_string(),
+                disposition.as_str().to_owned(),
+            ]);
+            *counts.entry(disposition.as_str()).or_insert(0) += 1;
+        }
+
+        // Like --list-tracks, build the whole table first so that --albums output doesn't
+        // interleave
+        let mut table = String::new();
+        if self.albums {
+            let csv_file = self.input_csv.as_deref().expect("input_csv is required");
+            table += &format!("{}:\n", csv_file.display());
+        }
+        table += &format_table(&rows);
+        let counts = counts
+            .iter()
+            .map(|(status, count)| format!("{count} {status}"))
+            .collect::<Vec<_>>();
+        if !counts.is_empty() {
+            table += &format!("{}\n", counts.join(", "));
+        }
+        print!("{table}");
+        Ok(())
+    }
+
+    /// Write an album metadata sidecar file to the output directory for --sidecar
+    fn write_sidecar(&self, format: SidecarFormat, tracks: &[Track]) -> Result<()> {
+        let name = match format {
+            SidecarFormat::Json => "album.json",
+            SidecarFormat::Nfo => "album.nfo",
+        };
+        let path = self.output_dir().join(name);
+        let mut tracks = tracks
+            .iter()
+            .map(|track| {
+                let output = self.converted_output(track)?;
+                Ok(SidecarTrack {
+                    disc: track.disc,
+                    track: track.track,
+                    title: &track.title,
+                    artist: self.track_artist(track)?,
+                    file: self.listed_path(&path, &output),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        tracks.sort_by_key(|t| (t.disc, t.track));
+
+        let album = Sidecar {
+            album: self.album_title.as_deref(),
+            album_artist: self.album_artist.as_deref(),
+            date: self.date.as_deref(),
+            tracks,
+        };
+        let contents = match format {
+            SidecarFormat::Json => {
+                serde_json::to_string_pretty(&album).context("failed to serialize sidecar")? + "\n"
+            }
+            SidecarFormat::Nfo => album.to_nfo(),
+        };
+        std::fs::write(&path, contents)
+            .with_context(|| format!("failed to write {}", path.display()))
+    }
+
+    /// Write the resolved album and its tracks to a TOML file for --export-album
+    fn export_album(&self, path: &Path, tracks: &[Track]) -> Result<()> {
+        let tracks = tracks
+            .iter()
+            .map(|track| {
+                Ok(ExportTrack {
+                    file: self.input_file(track),
+                    disc: track.disc,
+                    track: track.track,
+                    title: &track.title,
+                    artist: self.track_artist(track)?,
+                    genre: track.genre.as_deref().map(|g| self.genre(g)),
+                    output: self.output_file(track)?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let album = AlbumExport {
+            album: self.album_title.as_deref(),
+            album_artist: self.album_artist.as_deref(),
+            date: self.date.as_deref(),
+            format: self.format,
+            tracks,
+        };
+        let contents = toml::to_string(&album).context("failed to serialize album")?;
+        std::fs::write(path, contents)
+            .with_context(|| format!("failed to write {}", path.display()))
+    }
+
+    /// Context for errors from an album in --albums mode
+    fn album_context(&self) -> String {
+        let csv_file = self.input_csv.as_deref().expect("input_csv is set");
+        format!("failed to convert album {}", csv_file.display())
+    }
+
+    /// Read and check every album CSV in the input directory (--albums mode)
+    fn prepare_albums(&self) -> Result<Batch> {
+        let csv_dir = self.input_csv.as_deref().expect("input_csv is required");
+        let csv_files = list_dir(csv_dir, &["csv"])?;
+        if csv_files.is_empty() {
+            bail!("no CSV files found in {}", csv_dir.display());
+        }
+        let mut metadata = match &self.batch_metadata {
+            Some(path) => read_batch_metadata(path)?,
+            None => HashMap::new(),
+        };
+
+        let albums = csv_files
+            .iter()
+            .map(|csv_file| {
+                let mut album = self.clone();
+                let name = csv_file.file_stem().unwrap_or_default();
+                if let Some(meta) = name.to_str().and_then(|name| metadata.remove(name)) {
+                    album.album_title = album.album_title.or(meta.album);
+                    album.album_artist = album.album_artist.or(meta.album_artist);
+                    album.date = album.date.or(meta.date);
+                    album.cover = album.cover.or(meta.cover);
+                }
+                album.output_dir = Some(self.output_dir().join(name));
+                album.input_csv = Some(csv_file.clone());
+                album
+            })
+            .collect::<Vec<_>>();
+
+        let mut unused = metadata.into_keys().collect::<Vec<_>>();
+        unused.sort();
+        for name in unused {
+            self.warn(format_args!(
+                "--batch-metadata has no album CSV named '{name}.csv'"
+            ))?;
+        }
+
+        Ok(albums
+            .into_par_iter()
+            .map(|mut album| {
+                let tracks = album
+                    .prepare_album()
+                    .with_context(|| album.album_context())?;
+                Ok((album, tracks))
+            })
+            .collect())
+    }
+
+    /// Convert the albums from prepare_albums (--albums mode). Albums are converted in parallel
+    /// using the same thread pool as their tracks, so the total number of ffmpeg processes is
+    /// still limited by --threads.
+    fn convert_albums(&self, prepared: Batch) -> Result<Summary> {
+        let results = prepared
+            .into_par_iter()
+            .map(|res| {
+                let (mut album, tracks) = res?;
+                album
+                    .convert_tracks(tracks)
+                    .with_context(|| album.album_context())
+            })
+            .collect::<Vec<_>>();
+
+        let mut summary = Summary::default();
+        for res in results {
+            match res {
+                Ok(album_summary) => summary.merge(album_summary),
+                Err(err) => {
+                    summary.error.get_or_insert(err);
+                }
+            }
+        }
+        Ok(summary)
+    }
+
+    /// Run --self-test in a temporary directory, and clean it up afterwards
+    fn self_test(&self) -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("albumconv-self-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).context("failed to create self-test directory")?;
+        let res = self.self_test_in(&dir);
+        let _ = std::fs::remove_dir_all(&dir);
+        res.context("self-test failed")?;
+        println!("Self-test passed");
+        Ok(())
+    }
+
+    fn self_test_in(&self, dir: &Path) -> Result<()> {
+        const TITLE: &str = "Self Test";
+        const ARTIST: &str = "albumconv";
+
+        let mut cmd = self.ffmpeg();
+        cmd.args(["-hide_banner", "-nostdin", "-f", "lavfi", "-i"]);
+        cmd.args(["sine=frequency=440:duration=1", "-y"]);
+        cmd.arg(dir.join("sine.wav"));
+        let output = self
+            .runner
+            .output(&mut cmd)
+            .context("failed to run ffmpeg")?;
+        if !output.status.success() {
+            bail!(
+                "failed to generate test audio:\n{}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+
+        let csv_file = dir.join("album.csv");
+        std::fs::write(
+            &csv_file,
+            format!("file,disc,track,title,artist\nsine.wav,1,1,{TITLE},{ARTIST}\n"),
+        )
+        .context("failed to write test CSV")?;
+
+        let mut test = self.clone();
+        test.input_dir = Some(dir.to_owned());
+        test.input_csv = Some(csv_file);
+        test.output_dir = Some(dir.join("out"));
+        test.only = None;
+        test.skip = None;
+        let tracks = test.prepare_album()?;
+        let summary = test.convert_tracks(tracks)?;
+        if let Some(err) = summary.error {
+            return Err(err);
+        }
+        let output_file = summary
+            .tracks
+            .first()
+            .and_then(|t| t.output.clone())
+            .ok_or_else(|| anyhow!("no output file was written"))?;
+
+        let mut cmd = self.ffmpeg();
+        cmd.args(["-hide_banner", "-nostdin", "-v", "error", "-i"]);
+        cmd.arg(&output_file);
+        cmd.args(["-f", "ffmetadata", "-"]);
+        let output = self
+            .runner
+            .output(&mut cmd)
+            .context("failed to run ffmpeg")?;
+        if !output.status.success() {
+            bail!(
+                "failed to decode {}:\n{}",
+                output_file.display(),
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+
+        let tags = String::from_utf8_lossy(&output.stdout).to_lowercase();
+        for (key, value) in [("title", TITLE), ("artist", ARTIST)] {
+            let expected = format!("{key}={value}").to_lowercase();
+            if !tags.lines().any(|line| line == expected) {
+                bail!("{} is missing the tag {key}={value}", output_file.display());
+            }
+        }
+        Ok(())
+    }
+
+    fn output_dir(&self) -> &Path {
+        self.output_dir
+            .as_deref()
+            .expect("output_dir is required unless using --pipe")
+    }
+}
+
+fn run() -> Result<()> {
+    let mut args = Args::parse();
+    if let Some(shell) = args.completion {
+        clap_complete::generate(shell, &mut Args::command(), "albumconv", &mut io::stdout());
+        return Ok(());
+    }
+    let color = match args.color {
+        _ if args.report_format == ReportFormat::Json => false,
+        ColorChoice::Auto => io::stdout().is_terminal(),
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+    };
+    USE_COLOR.store(color, Ordering::Relaxed);
+    if args.expand_env {
+        args.expand_env_vars()?;
+    }
+    args.started = StartTime::capture();
+    if let Some(dir) = &args.output_dir {
+        args.output_dir = Some(args.started.expand(dir));
+    }
+    args.validate()?;
+    if args.dump_config {
+        let json = serde_json::to_string_pretty(&args).context("failed to serialize options")?;
+        println!("{json}");
+        return Ok(());
+    }
+    if let Some(path) = args.genre_map.clone() {
+        args.load_genre_map(&path)?;
+    }
+    if let Some(path) = args.manifest.clone() {
+        args.open_manifest(&path)?;
+    }
+    if let Some(path) = &args.checksums {
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("failed to open {}", path.display()))?;
+        args.checksums_file = Some(Arc::new(Mutex::new(file)));
+    }
+    if let Some(limit) = args.max_inflight {
+        args.inflight = Some(Arc::new(Semaphore::new(limit.get())));
+    }
+
+    if args.needs_ffprobe() {
+        args.check_ffprobe()?;
+    }
+    if args.needs_metaflac() {
+        args.check_metaflac()?;
+    }
+    if args.flac_verify {
+        args.check_flac()?;
+    }
+    let converting = !args.list_tracks
+        && !args.dry_run
+        && !args.compare_existing
+        && args.normalize_filenames.is_none();
+    if converting && !args.rename_only {
+        args.check_encoder()?;
+    }
+
+    if args.pipe {
+        return args.convert_pipe();
+    }
+    if args.self_test {
+        return args.self_test();
+    }
+    if let Some(dir) = &args.normalize_filenames {
+        return args.normalize_filenames(dir);
+    }
+    if converting {
+        args.check_free_space()?;
+    }
+
+    if args.summary_on_sigint {
+        #[cfg(unix)]
+        install_sigint_handler()?;
+        #[cfg(not(unix))]
+        args.warn("--summary-on-sigint is only supported on Unix")?;
+    }
+
+    // Use a local thread pool rather than configuring rayon's global pool, which can only be done
+    // once per process. 0 threads means rayon's default of one per CPU core.
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(args.threads.unwrap_or(0))
+        .build()
+        .context("failed to initialize thread pool")?;
+    let summary = pool.install(|| args.convert_all())?;
+    if args.list_tracks || args.dry_run || args.compare_existing {
+        return Ok(());
+    }
+    match args.report_format {
+        ReportFormat::Text => println!("{summary}"),
+        ReportFormat::Json => {
+            let json = serde_json::to_string_pretty(&summary).context("failed to write report")?;
+            println!("{json}");
+            // the error is already part of the report, don't print it again
+            if summary.error.is_some() {
+                std::process::exit(1);
+            }
+        }
+    }
+
+    match summary.error {
+        Some(err) => Err(err),
+        None if summary.listed == 0 => std::process::exit(EXIT_NO_TRACKS),
+        None => Ok(()),
+    }
+}
+
+fn main() {
+    if let Err(err) = run() {
+        println!("{} {err:#}", Style::Red.paint("Error:"));
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::ExitStatus;
+
+    /// A Runner that records commands instead of running them. Every command succeeds. ffprobe
+    /// prints `probe_output`, and ffmpeg creates an empty file at its last argument, which is
+    /// always the output file. parse_args makes sure that the programs are named like this.
+    #[derive(Debug, Default)]
+    struct RecordingRunner {
+        probe_output: String,
+        commands: Mutex<Vec<Vec<String>>>,
+    }
+
+    impl Runner for RecordingRunner {
+        fn run(
+            &self,
+            cmd: &mut Command,
+            _stream_prefix: Option<&str>,
+            _on_stdout: Option<&(dyn Fn(&str) + Sync)>,
+        ) -> io::Result<Output> {
+            let argv = std::iter::once(cmd.get_program())
+                .chain(cmd.get_args())
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect::<Vec<_>>();
+            let mut stdout = Vec::new();
+            match argv[0].as_str() {
+                "ffprobe" => stdout = self.probe_output.clone().into_bytes(),
+                "ffmpeg" => {
+                    let output_file = Path::new(argv.last().expect("ffmpeg has arguments"));
+                    std::fs::write(output_file, "")?;
+                }
+                _ => (),
+            }
+            self.commands.lock().unwrap().push(argv);
+            Ok(Output {
+                status: ExitStatus::default(),
+                stdout,
+                stderr: Vec::new(),
+            })
+        }
+    }
+
+    /// A new empty directory for a test's files
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("albumconv-test-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Parse and validate options, followed by an input CSV and output directory
+    fn parse_args(options: &[&str], dir: &Path) -> Args {
+        let csv_file = dir.join("album.csv");
+        let output_dir = dir.join("out");
+        let argv = ["albumconv"]
+            .iter()
+            .chain(options)
+            .map(OsStr::new)
+            .chain([csv_file.as_os_str(), output_dir.as_os_str()]);
+        let mut args = Args::try_parse_from(argv).unwrap();
+        // ignore ALBUMCONV_FFMPEG and ALBUMCONV_FFPROBE, RecordingRunner goes by these names
+        args.ffmpeg = PathBuf::from("ffmpeg");
+        args.ffprobe = PathBuf::from("ffprobe");
+        args.validate().unwrap();
+        args
+    }
+
+    fn track(file: &str, title: &str, artist: &str) -> Track {
+        Track {
+            file: PathBuf::from(file),
+            title: title.to_owned(),
+            artist: Some(artist.to_owned()),
+            ..Default::default()
+        }
+    }
+
+    /// Convert a track with a RecordingRunner, and return the commands that were run. The temp
+    /// file name changes between runs, so the last argument of ffmpeg commands is replaced by
+    /// "TEMP".
+    fn convert(options: &[&str], track: &Track, probe_output: &str) -> Vec<Vec<String>> {
+        let dir = test_dir(&options.join("").replace(['-', ' ', '/'], ""));
+        let mut args = parse_args(options, &dir);
+        let runner = Arc::new(RecordingRunner {
+            probe_output: probe_output.to_owned(),
+            ..Default::default()
+        });
+        args.runner = runner.clone();
+        std::fs::create_dir_all(args.output_dir()).unwrap();
+        args.convert_track(track).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let mut commands = runner.commands.lock().unwrap().clone();
+        for argv in commands.iter_mut().filter(|argv| argv[0] == "ffmpeg") {
+            *argv.last_mut().unwrap() = "TEMP".to_owned();
+        }
+        commands
+    }
+
+    #[test]
+    fn ffmpeg_args_flac() {
+        let commands = convert(&[], &track("a.wav", "One", "X"), "");
+        assert_eq!(
+            commands,
+            [[
+                "ffmpeg",
+                "-hide_banner",
+                "-loglevel",
+                "error",
+                "-nostdin",
+                "-i",
+                "a.wav",
+                "-map",
+                "0:a",
+                "-metadata",
+                "title=One",
+                "-metadata",
+                "artist=X",
+                "-c:a",
+                "flac",
+                "-y",
+                "TEMP",
+            ]]
+        );
+    }
+
+    #[test]
+    fn ffmpeg_args_mp3() {
+        let options = [
+            "--format",
+            "mp3",
+            "--bitrate",
+            "192k",
+            "--id3-version",
+            "2.3",
+        ];
+        let commands = convert(&options, &track("a.wav", "One", "X"), "");
+        assert_eq!(
+            commands,
+            [[
+                "ffmpeg",
+                "-hide_banner",
+                "-loglevel",
+                "error",
+                "-nostdin",
+                "-i",
+                "a.wav",
+                "-map",
+                "0:a",
+                "-metadata",
+                "title=One",
+                "-metadata",
+                "artist=X",
+                "-c:a",
+                "libmp3lame",
+                "-id3v2_version",
+                "3",
+                "-b:a",
+                "192k",
+                "-y",
+                "TEMP",
+            ]]
+        );
+    }
+
+    #[test]
+    fn ffmpeg_args_range_and_16bit() {
+        let mut track = track("album.flac", "Two", "X");
+        track.disc = Some(1);
+        track.track = Some(2);
+        track.start = Some(Timestamp(30.0));
+        track.end = Some(Timestamp(90.5));
+        let options = ["--force-16bit", "--album-title", "LP"];
+        let commands = convert(&options, &track, "");
+        assert_eq!(
+            commands,
+            [[
+                "ffmpeg",
+                "-hide_banner",
+                "-loglevel",
+                "error",
+                "-nostdin",
+                "-ss",
+                "30",
+                "-to",
+                "90.5",
+                "-i",
+                "album.flac",
+                "-map",
+                "0:a",
+                "-metadata",
+                "title=Two",
+                "-metadata",
+                "artist=X",
+                "-metadata",
+                "album=LP",
+                "-metadata",
+                "disc=1",
+                "-metadata",
+                "track=2",
+                "-c:a",
+                "flac",
+                "-sample_fmt",
+                "s16",
+                "-y",
+                "TEMP",
+            ]]
+        );
+    }
+
+    #[test]
+    fn skip_if_tagged_probes_input() {
+        let probe =
+            r#"{"streams": [{"codec_name": "flac"}], "format": {"tags": {"TITLE": "One"}}}"#;
+        let commands = convert(&["--skip-if-tagged"], &track("a.flac", "One", "X"), probe);
+        // the artist tag is missing, so the file is converted after probing it
+        assert_eq!(commands.len(), 2);
+        assert_eq!(
+            commands[0],
+            [
+                "ffprobe",
+                "-v",
+                "error",
+                "-select_streams",
+                "a:0",
+                "-of",
+                "json",
+                "-show_entries",
+                "format_tags:stream=codec_name:stream_tags",
+                "a.flac",
+            ]
+        );
+        assert_eq!(commands[1][0], "ffmpeg");
+
+        let probe = r#"{"streams": [{"codec_name": "flac"}],
+                        "format": {"tags": {"TITLE": "One", "ARTIST": "X"}}}"#;
+        let commands = convert(&["--skip-if-tagged"], &track("a.flac", "One", "X"), probe);
+        assert_eq!(commands.len(), 1, "an already tagged file isn't converted");
+    }
+
+    #[test]
+    fn ffmpeg_extra_args_order() {
+        let options = [
+            "--ffmpeg-input-arg=-f",
+            "--ffmpeg-input-arg=s16le",
+            "--ffmpeg-output-arg=-af",
+            "--ffmpeg-output-arg=atempo=2",
+        ];
+        let commands = convert(&options, &track("a.raw", "One", "X"), "");
+        let argv = &commands[0];
+        let input = argv.iter().position(|arg| arg == "-i").unwrap();
+        assert_eq!(argv[input - 2..=input + 1], ["-f", "s16le", "-i", "a.raw"]);
+        let overwrite = argv.iter().position(|arg| arg == "-y").unwrap();
+        assert_eq!(argv[overwrite - 2..], ["-af", "atempo=2", "-y", "TEMP"]);
+    }
+
+    #[test]
+    fn meta_tags_are_sorted() {
+        let options = [
+            "--meta",
+            "zeta=last",
+            "--meta",
+            "comment=vinyl",
+            "--meta",
+            "ENCODER=albumconv",
+            "--meta",
+            "comment=tape",
+        ];
+        let expected = [
+            "title=One",
+            "artist=X",
+            "ENCODER=albumconv",
+            "comment=tape",
+            "zeta=last",
+        ];
+        for _ in 0..3 {
+            let commands = convert(&options, &track("a.wav", "One", "X"), "");
+            let tags = commands[0]
+                .windows(2)
+                .filter(|pair| pair[0] == "-metadata")
+                .map(|pair| pair[1].as_str())
+                .collect::<Vec<_>>();
+            assert_eq!(tags, expected);
+        }
+    }
+
+    #[test]
+    fn csv_absolute_and_relative_files() {
+        let dir = test_dir("input-paths");
+        let absolute = dir.join("elsewhere").join("b.wav");
+        std::fs::write(
+            dir.join("album.csv"),
+            format!(
+                "file,title,artist\na.wav,One,X\n{},Two,X\ndisc2/c.wav,Three,X\n",
+                absolute.display()
+            ),
+        )
+        .unwrap();
+        let input_dir = dir.join("in");
+        let mut args = parse_args(&["--input-dir", input_dir.to_str().unwrap()], &dir);
+        let tracks = args.read_tracks().unwrap();
+        let inputs = tracks
+            .iter()
+            .map(|track| args.input_file(track).into_owned())
+            .collect::<Vec<_>>();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            inputs,
+            [
+                input_dir.join("a.wav"),
+                absolute,
+                input_dir.join("disc2").join("c.wav"),
+            ]
+        );
+    }
+
+    #[test]
+    fn sha256_file_known_answers() {
+        let dir = test_dir("sha256");
+        let empty = dir.join("empty");
+        let abc = dir.join("abc");
+        std::fs::write(&empty, "").unwrap();
+        std::fs::write(&abc, "abc").unwrap();
+        let hashes = [sha256_file(&empty).unwrap(), sha256_file(&abc).unwrap()];
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            hashes,
+            [
+                "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+                "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            ]
+        );
+    }
+
+    #[test]
+    fn csv_error_line_after_comments() {
+        let dir = test_dir("csv-comments");
+        std::fs::write(
+            dir.join("album.csv"),
+            "# album: Z\n# date: 2001\nfile,title,disc\na.wav,One,1\nb.wav,Two,x\n",
+        )
+        .unwrap();
+        let mut args = parse_args(&[], &dir);
+        let err = format!("{:#}", args.read_tracks().unwrap_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(err.contains("(line: 5,"), "{err}");
+    }
+
+    #[test]
+    fn flat_colliding_names() {
+        let dir = test_dir("flat-collision");
+        std::fs::write(
+            dir.join("album.csv"),
+            "file,disc,track,title,artist\na.wav,1,1,Intro,X\nb.wav,2,1,Intro,X\n",
+        )
+        .unwrap();
+        let mut args = parse_args(&["--flat"], &dir);
+        let err = args.prepare_album().unwrap_err().to_string();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(err.starts_with("track 1 (a.wav, line 2 of "), "{err}");
+        assert!(err.contains(" and track 2 (b.wav, line 3 of "), "{err}");
+        assert!(
+            err.ends_with("use --hash-on-collision to tell them apart"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn name_template_subdirectory() {
+        let dir = test_dir("template-subdir");
+        let mut args = parse_args(&["--name-template", "{artist}/{title}.flac"], &dir);
+        args.runner = Arc::new(RecordingRunner::default());
+        args.convert_track(&track("a.wav", "One", "X")).unwrap();
+        let output_file = args.output_dir().join("X").join("One.flac");
+        let exists = output_file.is_file();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(exists, "{} wasn't created", output_file.display());
+    }
+
+    #[test]
+    fn cover_first_track_of_each_disc() {
+        let dir = test_dir("cover-first");
+        let cover = dir.join("cover.jpg");
+        std::fs::write(&cover, "").unwrap();
+        std::fs::write(
+            dir.join("album.csv"),
+            "file,disc,title,artist\na.wav,1,One,X\nb.wav,1,Two,X\nc.wav,2,Three,X\n",
+        )
+        .unwrap();
+        let cover_arg = cover.to_str().unwrap();
+        let mut args = parse_args(&["--cover", cover_arg, "--cover-first-track-only"], &dir);
+        let runner = Arc::new(RecordingRunner::default());
+        args.runner = runner.clone();
+        for track in args.prepare_album().unwrap() {
+            args.convert_track(&track).unwrap();
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+        let with_cover = runner
+            .commands
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|argv| argv.iter().any(|arg| arg == cover_arg))
+            .collect::<Vec<_>>();
+        assert_eq!(with_cover, [true, false, true]);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn input_path_slash_styles() {
+        let dir = test_dir("slashes");
+        let args = parse_args(&["--input-dir", r"C:\music\album"], &dir);
+        std::fs::remove_dir_all(&dir).unwrap();
+        let forward = args.input_path(Path::new("disc1/track.wav"));
+        let backward = args.input_path(Path::new(r"disc1\track.wav"));
+        assert_eq!(forward.as_os_str(), r"C:\music\album\disc1\track.wav");
+        assert_eq!(forward.as_os_str(), backward.as_os_str());
     }
 }